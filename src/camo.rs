@@ -1,22 +1,95 @@
 use crate::Configuration;
-use anyhow::Result;
+use anyhow::{Context, Result};
+use base64::Engine;
 use camo_url::CamoConfig;
+use hmac::{Hmac, Mac};
 use url::Url;
 
+type HmacSha1 = Hmac<sha1::Sha1>;
+type HmacSha256 = Hmac<sha2::Sha256>;
+
+/// URL format of the image proxy `camo_url` points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProxyScheme {
+    /// Camo, `<host>/<hex hmac-sha1>?url=<url>`
+    #[default]
+    Camo,
+    /// go-camo in base64 mode, `<host>/<base64 hmac-sha1>/<base64 url>`
+    GoCamo,
+    /// imgproxy, `<host>/<signature>/<options>/<base64 url>`
+    Imgproxy,
+    /// Thumbor, `<host>/<signature>/<options>/<url>`
+    Thumbor,
+}
+
+impl std::str::FromStr for ProxyScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "camo" => Ok(Self::Camo),
+            "go-camo" | "gocamo" => Ok(Self::GoCamo),
+            "imgproxy" => Ok(Self::Imgproxy),
+            "thumbor" => Ok(Self::Thumbor),
+            _ => anyhow::bail!("unknown proxy scheme {:?}", s),
+        }
+    }
+}
+
+/// Matches a host against a comma separated list of hosts, each entry also matching its
+/// subdomains.
+fn host_listed(list: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    list.split(',')
+        .map(|x| x.trim().trim_start_matches("*.").trim_end_matches('.'))
+        .filter(|x| !x.is_empty())
+        .any(|entry| {
+            let start = host.len().saturating_sub(entry.len());
+            host[start..].eq_ignore_ascii_case(entry)
+                && (start == 0 || host.as_bytes()[start - 1] == b'.')
+        })
+}
+
+/// Whether images from this URL must not be returned at all.
+pub fn is_denied(config: &Configuration, url: &Url) -> bool {
+    url.host_str()
+        .is_some_and(|host| host_listed(&config.denied_image_hosts, host))
+}
+
+/// Whether this URL is on a trusted HTTPS host and can be used without the proxy.
+fn bypasses_camo(config: &Configuration, url: &Url) -> bool {
+    url.scheme() == "https"
+        && url
+            .host_str()
+            .is_some_and(|host| host_listed(&config.camo_bypass_hosts, host))
+}
+
 #[tracing::instrument(skip(config))]
 pub fn camo_url(config: &Configuration, url: &Url) -> Result<Url> {
-    let camo_key = if let Some(key) = config.camo_key.clone() {
-        key
-    } else {
-        return Ok(url.clone());
-    };
-    let camo_key = hex::encode(camo_key);
-    let camo_host = if let Some(host) = config.camo_host.clone() {
-        host
-    } else {
+    if bypasses_camo(config, url) {
         return Ok(url.clone());
+    }
+    if let Some(url) = crate::thumbnail::thumbnail_url(config, url)? {
+        return Ok(url);
+    }
+    let (camo_key, camo_host) = match (&config.camo_key, &config.camo_host) {
+        (Some(key), Some(host)) => (key, host),
+        _ => return Ok(url.clone()),
     };
-    let camo = CamoConfig::new(camo_key, camo_host);
+    let options = config.proxy_options.as_deref();
+    match config.proxy_scheme {
+        ProxyScheme::Camo => camo(camo_key, camo_host, url),
+        ProxyScheme::GoCamo => go_camo(camo_key, camo_host, url),
+        ProxyScheme::Imgproxy => {
+            let salt = config.proxy_salt.as_deref().unwrap_or_default();
+            imgproxy(camo_key, salt, options, camo_host, url)
+        }
+        ProxyScheme::Thumbor => thumbor(camo_key, options, camo_host, url),
+    }
+}
+
+fn camo(camo_key: &str, camo_host: &str, url: &Url) -> Result<Url> {
+    let camo = CamoConfig::new(hex::encode(camo_key), camo_host);
     let camo = match camo {
         Err(e) => anyhow::bail!(format!("camo config invalid: {}", e)),
         Ok(camo) => camo,
@@ -27,3 +100,154 @@ pub fn camo_url(config: &Configuration, url: &Url) -> Result<Url> {
         Err(e) => anyhow::bail!(format!("camo url invalid: {}", e)),
     }
 }
+
+fn go_camo(key: &str, host: &str, url: &Url) -> Result<Url> {
+    let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let mut mac = HmacSha1::new_from_slice(key.as_bytes())?;
+    mac.update(url.as_str().as_bytes());
+    let digest = b64.encode(mac.finalize().into_bytes());
+    join_host(host, &format!("{}/{}", digest, b64.encode(url.as_str())))
+}
+
+/// imgproxy takes its key and salt hex encoded, the same as in its own configuration.
+fn imgproxy(key: &str, salt: &str, options: Option<&str>, host: &str, url: &Url) -> Result<Url> {
+    let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let key = hex::decode(key).context("imgproxy key is not valid hex")?;
+    let salt = hex::decode(salt).context("imgproxy salt is not valid hex")?;
+    let path = match options
+        .map(|x| x.trim_matches('/'))
+        .filter(|x| !x.is_empty())
+    {
+        Some(options) => format!("/{}/{}", options, b64.encode(url.as_str())),
+        None => format!("/{}", b64.encode(url.as_str())),
+    };
+    let mut mac = HmacSha256::new_from_slice(&key)?;
+    mac.update(&salt);
+    mac.update(path.as_bytes());
+    let signature = b64.encode(mac.finalize().into_bytes());
+    join_host(host, &format!("{}{}", signature, path))
+}
+
+fn thumbor(key: &str, options: Option<&str>, host: &str, url: &Url) -> Result<Url> {
+    let b64 = base64::engine::general_purpose::URL_SAFE;
+    let path = match options
+        .map(|x| x.trim_matches('/'))
+        .filter(|x| !x.is_empty())
+    {
+        Some(options) => format!("{}/{}", options, url),
+        None => url.to_string(),
+    };
+    let mut mac = HmacSha1::new_from_slice(key.as_bytes())?;
+    mac.update(path.as_bytes());
+    let signature = b64.encode(mac.finalize().into_bytes());
+    join_host(host, &format!("{}/{}", signature, path))
+}
+
+fn join_host(host: &str, path: &str) -> Result<Url> {
+    Url::parse(&format!("{}/{}", host.trim_end_matches('/'), path)).context("proxy url invalid")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(scheme: ProxyScheme) -> Configuration {
+        Configuration {
+            camo_key: Some("secret".to_string()),
+            camo_host: Some("https://proxy.example.com".to_string()),
+            proxy_scheme: scheme,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_go_camo_url() -> Result<()> {
+        let url = Url::parse("https://example.com/image.png")?;
+        let proxied = camo_url(&config(ProxyScheme::GoCamo), &url)?;
+        let segments: Vec<&str> = proxied.path_segments().unwrap().collect();
+        let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        assert_eq!(url.as_str().as_bytes(), b64.decode(segments[1])?);
+        assert_eq!(20, b64.decode(segments[0])?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_imgproxy_url() -> Result<()> {
+        let config = Configuration {
+            camo_key: Some(
+                "943b421c9eb07c830af81030552c86009268de4e532ba2ee2eab8247c6da0881".to_string(),
+            ),
+            proxy_salt: Some(
+                "520f986b998545b4785e0defbc4f3c1203f22de2374a3d53cb7a7fe9fea309c5".to_string(),
+            ),
+            proxy_options: Some("rs:fill:300:400:0/g:sm".to_string()),
+            ..config(ProxyScheme::Imgproxy)
+        };
+        let url = Url::parse("http://example.com/images/curiosity.jpg")?;
+        assert_eq!(
+            "https://proxy.example.com/nr1oJKBkcmSVAgfqkzJY8vDaD3vvsuAGHKaZ05g3k5U/rs:fill:300:400:0/g:sm/aHR0cDovL2V4YW1wbGUuY29tL2ltYWdlcy9jdXJpb3NpdHkuanBn",
+            camo_url(&config, &url)?.as_str()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_thumbor_url() -> Result<()> {
+        let config = Configuration {
+            proxy_options: Some("fit-in/300x300".to_string()),
+            ..config(ProxyScheme::Thumbor)
+        };
+        let url = Url::parse("https://example.com/image.png")?;
+        assert_eq!(
+            "https://proxy.example.com/Q2daZLV_9zjqW56DCtCBJO_DumI=/fit-in/300x300/https://example.com/image.png",
+            camo_url(&config, &url)?.as_str()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bypass_and_deny_hosts() -> Result<()> {
+        let config = Configuration {
+            camo_bypass_hosts: "derpicdn.net, *.example.org".to_string(),
+            denied_image_hosts: "bad.example.com".to_string(),
+            ..config(ProxyScheme::GoCamo)
+        };
+        for url in [
+            "https://derpicdn.net/img/1.png",
+            "https://static.derpicdn.net/img/1.png",
+            "https://cdn.example.org/1.png",
+        ] {
+            let url = Url::parse(url)?;
+            assert_eq!(url, camo_url(&config, &url)?);
+        }
+        for url in [
+            "http://derpicdn.net/img/1.png",
+            "https://evilderpicdn.net/img/1.png",
+        ] {
+            let url = Url::parse(url)?;
+            assert_ne!(url, camo_url(&config, &url)?);
+        }
+        assert!(is_denied(
+            &config,
+            &Url::parse("http://bad.example.com/1.png")?
+        ));
+        assert!(is_denied(
+            &config,
+            &Url::parse("https://a.bad.example.com/1.png")?
+        ));
+        assert!(!is_denied(
+            &config,
+            &Url::parse("https://example.com/1.png")?
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unconfigured() -> Result<()> {
+        let url = Url::parse("https://example.com/image.png")?;
+        assert_eq!(url, camo_url(&Configuration::default(), &url)?);
+        assert!("nope".parse::<ProxyScheme>().is_err());
+        assert_eq!(ProxyScheme::GoCamo, "go-camo".parse::<ProxyScheme>()?);
+        Ok(())
+    }
+}
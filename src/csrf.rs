@@ -0,0 +1,99 @@
+use crate::Configuration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Header Philomena's frontend uses to send the CSRF token along with fetch requests.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Checks a CSRF token sent along with a scrape request.
+///
+/// If no `CSRF_SECRET` is configured, any non-empty token is accepted. Otherwise the token
+/// must be of the form `<payload>.<issued at>.<signature>`, where the issue time is in unix
+/// seconds, no older than `CSRF_TOKEN_MAX_AGE`, and the signature is the hex encoded
+/// HMAC-SHA256 of `<payload>.<issued at>` using the shared secret. The payload should bind
+/// the token to the session it was issued for.
+#[tracing::instrument(skip(config, token))]
+pub fn csrf_valid(config: &Configuration, token: Option<&str>) -> bool {
+    csrf_valid_at(config, token, chrono::Utc::now().timestamp())
+}
+
+/// Tokens issued up to this many seconds in the future are accepted, for clock skew.
+const MAX_CLOCK_SKEW: i64 = 60;
+
+fn csrf_valid_at(config: &Configuration, token: Option<&str>, now: i64) -> bool {
+    let token = match token.map(|x| x.trim()) {
+        None => return false,
+        Some("") => return false,
+        Some(token) => token,
+    };
+    let secret = match config.csrf_secret.as_ref() {
+        None => return true,
+        Some(secret) => secret,
+    };
+    let (signed, signature) = match token.rsplit_once('.') {
+        None => return false,
+        Some(v) => v,
+    };
+    let issued_at = match signed.rsplit_once('.').map(|(_, x)| x.parse::<i64>()) {
+        Some(Ok(v)) => v,
+        _ => return false,
+    };
+    let max_age = i64::try_from(config.csrf_token_max_age).unwrap_or(i64::MAX);
+    if issued_at > now.saturating_add(MAX_CLOCK_SKEW) || now.saturating_sub(issued_at) > max_age {
+        return false;
+    }
+    let signature = match hex::decode(signature) {
+        Err(_) => return false,
+        Ok(v) => v,
+    };
+    let mut mac = match HmacSha256::new_from_slice(secret.as_bytes()) {
+        Err(_) => return false,
+        Ok(v) => v,
+    };
+    mac.update(signed.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sign(secret: &str, payload: &str, issued_at: i64) -> String {
+        let signed = format!("{payload}.{issued_at}");
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(signed.as_bytes());
+        format!("{}.{}", signed, hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn test_csrf_presence() {
+        let config = Configuration::default();
+        assert!(!csrf_valid(&config, None));
+        assert!(!csrf_valid(&config, Some("  ")));
+        assert!(csrf_valid(&config, Some("anything")));
+    }
+
+    #[test]
+    fn test_csrf_hmac() {
+        let config = Configuration {
+            csrf_secret: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let now = 1_700_000_000;
+        let valid = |token: &str| csrf_valid_at(&config, Some(token), now);
+        assert!(valid(&sign("secret", "session-1", now - 10)));
+        assert!(!valid(&sign("other", "session-1", now - 10)));
+        assert!(!valid("session-1"));
+        assert!(!valid("session-1.zz"));
+        assert!(!valid(&format!("session-1.{now}.zz")));
+        // expired and issued in the future
+        assert!(!valid(&sign("secret", "session-1", now - 3601)));
+        assert!(!valid(&sign("secret", "session-1", now + 3600)));
+        // the issue time is covered by the signature
+        let token = sign("secret", "session-1", now - 4000);
+        let signature = token.rsplit_once('.').unwrap().1;
+        assert!(!valid(&format!("session-1.{now}.{signature}")));
+    }
+}
@@ -0,0 +1,192 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, trace, warn};
+use url::Url;
+
+use crate::{
+    scraper::{self, ScrapeResult},
+    Configuration, State,
+};
+
+pub type JobCache = moka::future::Cache<String, Job>;
+
+#[derive(Serialize, Debug, Clone, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Finished { result: ScrapeResult },
+}
+
+#[derive(Serialize, Debug, Clone, utoipa::ToSchema)]
+pub struct Job {
+    pub id: String,
+    pub url: String,
+    #[serde(flatten)]
+    pub status: JobStatus,
+    #[serde(skip)]
+    callback_url: Option<Url>,
+}
+
+/// In-process queue of scrape jobs, worked on by a fixed number of workers.
+#[derive(Clone)]
+pub struct JobQueue {
+    jobs: JobCache,
+    sender: mpsc::Sender<String>,
+    receiver: Arc<Mutex<mpsc::Receiver<String>>>,
+}
+
+impl JobQueue {
+    pub fn new(config: &Configuration) -> Self {
+        let (sender, receiver) = mpsc::channel(config.job_queue_size.max(1));
+        Self {
+            jobs: moka::future::CacheBuilder::new(10000)
+                .time_to_live(std::time::Duration::from_secs(60 * 60))
+                .build(),
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+
+    /// Queues a new job, returns `None` if the queue is full.
+    #[tracing::instrument(skip(self))]
+    pub async fn submit(&self, url: String, callback_url: Option<Url>) -> Result<Option<Job>> {
+        if let Some(callback_url) = &callback_url {
+            scraper::ensure_public_url(callback_url)
+                .await
+                .context("callback URL must be a public http or https URL")?;
+        }
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            url,
+            status: JobStatus::Queued,
+            callback_url,
+        };
+        self.jobs.insert(job.id.clone(), job.clone()).await;
+        match self.sender.try_send(job.id.clone()) {
+            Ok(()) => Ok(Some(job)),
+            Err(_) => {
+                debug!("job queue full, rejecting job");
+                self.jobs.invalidate(&job.id).await;
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.get(id).await
+    }
+
+    async fn set_status(&self, job: &mut Job, status: JobStatus) {
+        job.status = status;
+        self.jobs.insert(job.id.clone(), job.clone()).await;
+    }
+}
+
+/// Starts the workers processing the job queue of the given state.
+pub fn spawn_workers(state: Arc<State>) {
+    for worker in 0..state.config.job_workers {
+        let state = state.clone();
+        tokio::spawn(async move {
+            trace!("job worker {} started", worker);
+            loop {
+                let id = state.jobs.receiver.lock().await.recv().await;
+                let id = match id {
+                    None => break,
+                    Some(id) => id,
+                };
+                run_job(&state, &id).await;
+            }
+            trace!("job worker {} stopped", worker);
+        });
+    }
+}
+
+#[tracing::instrument(skip(state))]
+async fn run_job(state: &State, id: &str) {
+    let mut job = match state.jobs.get(id).await {
+        None => {
+            debug!("job expired before it could run");
+            return;
+        }
+        Some(job) => job,
+    };
+    state.jobs.set_status(&mut job, JobStatus::Running).await;
+    let res = state
+        .result_cache
+        .try_get_with(
+            job.url.clone(),
+            scraper::scrape(&state.config, state, &job.url),
+        )
+        .await;
+    let result = match res {
+        Ok(Some(res)) => res,
+        Ok(None) => ScrapeResult::Err("URL invalid".to_string().into()),
+        Err(e) => {
+            sentry::integrations::anyhow::capture_anyhow(&e);
+            ScrapeResult::from_err(e)
+        }
+    };
+    state
+        .jobs
+        .set_status(&mut job, JobStatus::Finished { result })
+        .await;
+    if let Some(callback_url) = &job.callback_url {
+        if let Err(e) = send_callback(&state.config, callback_url, &job).await {
+            warn!("could not deliver job callback: {:?}", e);
+        }
+    }
+}
+
+#[tracing::instrument(skip(config, job))]
+async fn send_callback(config: &Configuration, callback_url: &Url, job: &Job) -> Result<()> {
+    // checked again, the host may resolve elsewhere by now
+    scraper::safe_client(config, callback_url)
+        .await?
+        .post(callback_url.clone())
+        .json(job)
+        .send()
+        .await
+        .context("callback request failed")?
+        .error_for_status()
+        .context("callback returned error code")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_job_queue_bounded() -> Result<()> {
+        let config = Configuration {
+            job_queue_size: 1,
+            ..Default::default()
+        };
+        let queue = JobQueue::new(&config);
+        let job = tokio_test::block_on(queue.submit("https://example.com".to_string(), None))?;
+        let job = job.expect("first job must be queued");
+        assert!(matches!(
+            tokio_test::block_on(queue.get(&job.id)).map(|x| x.status),
+            Some(JobStatus::Queued)
+        ));
+        let job = tokio_test::block_on(queue.submit("https://example.com".to_string(), None))?;
+        assert!(job.is_none());
+        for callback in [
+            "file:///etc/passwd",
+            "http://127.0.0.1:8080/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/",
+        ] {
+            let callback = Url::parse(callback)?;
+            assert!(tokio_test::block_on(
+                queue.submit("https://example.com".to_string(), Some(callback))
+            )
+            .is_err());
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,16 @@
 use std::sync::Arc;
 
 use anyhow::Result;
-use axum::routing::get;
+use axum::routing::{get, post};
 use envconfig::Envconfig;
 use tracing::{info, trace, Level};
 
 mod camo;
+mod csrf;
+mod jobs;
+mod proxy;
 mod scraper;
+mod thumbnail;
 mod web;
 
 #[derive(Envconfig, Clone, securefmt::Debug)]
@@ -19,9 +23,33 @@ pub struct Configuration {
     allowed_origins: String,
     #[envconfig(from = "CHECK_CSRF_PRESENCE", default = "false")]
     check_csrf_presence: bool,
+    #[envconfig(from = "CSRF_SECRET")]
+    #[sensitive]
+    csrf_secret: Option<String>,
+    #[envconfig(from = "CSRF_TOKEN_MAX_AGE", default = "3600")]
+    csrf_token_max_age: u64,
     #[envconfig(from = "TUMBLR_API_KEY")]
     #[sensitive]
     tumblr_api_key: Option<String>,
+    #[envconfig(from = "TUMBLR_CONSUMER_SECRET")]
+    #[sensitive]
+    tumblr_consumer_secret: Option<String>,
+    #[envconfig(from = "TUMBLR_OAUTH_TOKEN")]
+    #[sensitive]
+    tumblr_oauth_token: Option<String>,
+    #[envconfig(from = "TUMBLR_OAUTH_TOKEN_SECRET")]
+    #[sensitive]
+    tumblr_oauth_token_secret: Option<String>,
+    #[envconfig(from = "TUMBLR_OAUTH2_REFRESH_TOKEN")]
+    #[sensitive]
+    tumblr_oauth2_refresh_token: Option<String>,
+    #[envconfig(from = "TUMBLR_OAUTH2_STATE_FILE")]
+    tumblr_oauth2_state_file: Option<String>,
+    #[envconfig(
+        from = "TUMBLR_IP_RANGES",
+        default = "66.6.32.0/23,66.6.44.0/24,74.114.152.0/22"
+    )]
+    tumblr_ip_ranges: String,
     #[envconfig(from = "HTTP_PROXY")]
     #[sensitive]
     proxy_url: Option<String>,
@@ -30,6 +58,17 @@ pub struct Configuration {
     camo_key: Option<String>,
     #[envconfig(from = "CAMO_HOST")]
     camo_host: Option<String>,
+    #[envconfig(from = "CAMO_BYPASS_HOSTS", default = "")]
+    camo_bypass_hosts: String,
+    #[envconfig(from = "DENIED_IMAGE_HOSTS", default = "")]
+    denied_image_hosts: String,
+    #[envconfig(from = "PROXY_SCHEME", default = "camo")]
+    proxy_scheme: camo::ProxyScheme,
+    #[envconfig(from = "PROXY_SALT")]
+    #[sensitive]
+    proxy_salt: Option<String>,
+    #[envconfig(from = "PROXY_OPTIONS")]
+    proxy_options: Option<String>,
     #[envconfig(from = "ENABLE_GET_REQUEST", default = "false")]
     enable_get_request: bool,
     #[envconfig(from = "PREFERRED_NITTER_INSTANCE_HOST")]
@@ -53,6 +92,41 @@ pub struct Configuration {
         default = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:109.0) Gecko/20100101 Firefox/114.0"
     )]
     user_agent: String,
+    #[envconfig(from = "PROBE_IMAGES", default = "false")]
+    probe_images: bool,
+    #[envconfig(from = "PROBE_MAX_BYTES", default = "65536")]
+    probe_max_bytes: usize,
+    #[envconfig(from = "HASH_IMAGES", default = "false")]
+    hash_images: bool,
+    #[envconfig(from = "HASH_MAX_BYTES", default = "26214400")]
+    hash_max_bytes: usize,
+    #[envconfig(from = "DESCRIPTION_MAX_LENGTH", default = "50000")]
+    description_max_length: usize,
+    #[envconfig(from = "EXPAND_SHORT_LINKS", default = "false")]
+    expand_short_links: bool,
+    #[envconfig(from = "TAG_RULES_FILE")]
+    tag_rules_file: Option<String>,
+    #[envconfig(from = "JOB_WORKERS", default = "4")]
+    job_workers: usize,
+    #[envconfig(from = "JOB_QUEUE_SIZE", default = "100")]
+    job_queue_size: usize,
+    #[envconfig(from = "ENABLE_PROXY", default = "false")]
+    enable_proxy: bool,
+    #[envconfig(from = "PROXY_MAX_BYTES", default = "26214400")]
+    proxy_max_bytes: usize,
+    #[envconfig(from = "THUMBNAIL_KEY")]
+    #[sensitive]
+    thumbnail_key: Option<String>,
+    #[envconfig(from = "THUMBNAIL_BASE_URL")]
+    thumbnail_base_url: Option<url::Url>,
+    #[envconfig(from = "THUMBNAIL_CACHE_DIR")]
+    thumbnail_cache_dir: Option<String>,
+    #[envconfig(from = "THUMBNAIL_SIZE", default = "250")]
+    thumbnail_size: u32,
+    #[envconfig(from = "THUMBNAIL_MAX_BYTES", default = "26214400")]
+    thumbnail_max_bytes: usize,
+    #[envconfig(from = "THUMBNAIL_CACHE_MAX_BYTES", default = "1073741824")]
+    thumbnail_cache_max_bytes: u64,
 }
 
 #[derive(Clone)]
@@ -60,11 +134,17 @@ pub struct State {
     config: Configuration,
     parsed_allowed_origins: Vec<String>,
     result_cache: ResultCache,
-    tumblr_dns_cache: TumblrDnsCache,
+    tumblr_resolver: scraper::TumblrResolver,
+    tumblr_upsize_cache: TumblrUpsizeCache,
+    tumblr_auth: scraper::TumblrAuth,
+    tag_rules: Arc<scraper::TagRules>,
+    jobs: jobs::JobQueue,
 }
 
 pub type ResultCache = moka::future::Cache<String, Option<scraper::ScrapeResult>>;
 pub type TumblrDnsCache = moka::future::Cache<String, bool>;
+/// Largest known size of a Tumblr media file, only sizes that were found are kept.
+pub type TumblrUpsizeCache = moka::future::Cache<String, url::Url>;
 
 impl State {
     fn new(config: Configuration) -> Result<Self> {
@@ -75,6 +155,18 @@ impl State {
                 .filter(|x| !x.is_empty())
                 .map(|x| x.to_string())
                 .collect(),
+            jobs: jobs::JobQueue::new(&config),
+            tag_rules: Arc::new(scraper::TagRules::load(config.tag_rules_file.as_deref())?),
+            tumblr_resolver: scraper::TumblrResolver::new(
+                &config,
+                moka::future::CacheBuilder::new(1000)
+                    .initial_capacity(1000)
+                    .support_invalidation_closures()
+                    .time_to_idle(std::time::Duration::from_secs(10 * 60))
+                    .time_to_live(std::time::Duration::from_secs(100 * 60))
+                    .build(),
+            )?,
+            tumblr_auth: scraper::TumblrAuth::new(&config)?,
             config,
             result_cache: moka::future::CacheBuilder::new(1000)
                 .initial_capacity(1000)
@@ -82,30 +174,57 @@ impl State {
                 .time_to_idle(std::time::Duration::from_secs(10 * 60))
                 .time_to_live(std::time::Duration::from_secs(100 * 60))
                 .build(),
-            tumblr_dns_cache: moka::future::CacheBuilder::new(1000)
-                .initial_capacity(1000)
-                .support_invalidation_closures()
-                .time_to_idle(std::time::Duration::from_secs(10 * 60))
-                .time_to_live(std::time::Duration::from_secs(100 * 60))
+            tumblr_upsize_cache: moka::future::CacheBuilder::new(10000)
+                .time_to_live(std::time::Duration::from_secs(24 * 60 * 60))
                 .build(),
         })
     }
     pub fn is_allowed_origin(&self, origin: Option<&str>) -> bool {
         match origin {
             Some(origin) => {
-                let mut allowed = false;
-                for host in &self.parsed_allowed_origins {
-                    if host == origin {
-                        allowed = true;
-                    }
-                }
-                allowed || self.parsed_allowed_origins.is_empty()
+                self.parsed_allowed_origins.is_empty()
+                    || self
+                        .parsed_allowed_origins
+                        .iter()
+                        .any(|pattern| origin_matches(pattern, origin))
             }
             None => self.config.allow_empty_origin,
         }
     }
 }
 
+/// Matches an `Origin` header against an entry of `ALLOWED_ORIGINS`.
+///
+/// Entries without a scheme are compared against the host and port of the origin only.
+/// A `*` matches everything, a leading `*.` matches any subdomain of the given host.
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let pattern = pattern.trim();
+    if pattern == "*" {
+        return true;
+    }
+    let (pattern, origin) = match (pattern.split_once("://"), origin.split_once("://")) {
+        (Some((pscheme, pattern)), Some((oscheme, origin))) => {
+            if !pscheme.eq_ignore_ascii_case(oscheme) {
+                return false;
+            }
+            (pattern, origin)
+        }
+        (Some(_), None) => return false,
+        (None, Some((_, origin))) => (pattern, origin),
+        (None, None) => (pattern, origin),
+    };
+    let origin = origin.trim_end_matches('/');
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => {
+            let host = origin.len().saturating_sub(suffix.len());
+            host > 1
+                && origin[host..].eq_ignore_ascii_case(suffix)
+                && origin.as_bytes()[host - 1] == b'.'
+        }
+        None => pattern.eq_ignore_ascii_case(origin),
+    }
+}
+
 impl Default for Configuration {
     fn default() -> Self {
         let s = Self {
@@ -115,10 +234,23 @@ impl Default for Configuration {
                 .unwrap(),
             allowed_origins: "".to_string(),
             check_csrf_presence: false,
+            csrf_secret: None,
+            csrf_token_max_age: 3600,
             tumblr_api_key: std::env::var("TUMBLR_API_KEY").ok(),
+            tumblr_consumer_secret: None,
+            tumblr_oauth_token: None,
+            tumblr_oauth_token_secret: None,
+            tumblr_oauth2_refresh_token: None,
+            tumblr_oauth2_state_file: None,
+            tumblr_ip_ranges: "66.6.32.0/23,66.6.44.0/24,74.114.152.0/22".to_string(),
             proxy_url: None,
             camo_host: None,
             camo_key: None,
+            camo_bypass_hosts: "".to_string(),
+            denied_image_hosts: "".to_string(),
+            proxy_scheme: camo::ProxyScheme::Camo,
+            proxy_salt: None,
+            proxy_options: None,
             enable_get_request: false,
             preferred_nitter_instance_host: None,
             log_level: Level::INFO,
@@ -131,6 +263,23 @@ impl Default for Configuration {
             user_agent:
                 "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:109.0) Gecko/20100101 Firefox/114.0"
                     .to_string(),
+            probe_images: false,
+            probe_max_bytes: 65536,
+            hash_images: false,
+            hash_max_bytes: 26214400,
+            description_max_length: 50000,
+            expand_short_links: false,
+            tag_rules_file: None,
+            job_workers: 4,
+            job_queue_size: 100,
+            enable_proxy: false,
+            proxy_max_bytes: 26214400,
+            thumbnail_key: None,
+            thumbnail_base_url: None,
+            thumbnail_cache_dir: None,
+            thumbnail_size: 250,
+            thumbnail_max_bytes: 26214400,
+            thumbnail_cache_max_bytes: 1073741824,
         };
         trace!("created config: {:?}", s);
         s
@@ -207,14 +356,46 @@ async fn main_start() -> Result<()> {
         sentry::init((url.to_string(), opts))
     });
     let state = Arc::new(State::new(config.clone())?);
+    jobs::spawn_workers(state.clone());
     let astate = state.clone();
+    let (scrape_route, scrape_v2_route, events_route) = if config.enable_get_request {
+        (
+            get(web::scrape).post(web::scrape_post),
+            get(web::scrape_v2).post(web::scrape_v2_post),
+            get(web::scrape_events).post(web::scrape_events_post),
+        )
+    } else {
+        (
+            post(web::scrape_post),
+            post(web::scrape_v2_post),
+            post(web::scrape_events_post),
+        )
+    };
     let app = axum::Router::new()
-        .route("/images/scrape", get(web::scrape).post(web::scrape_post))
-        .layer(axum::middleware::from_fn(move |a, b| {
-            let state = astate.clone();
-            web::origin_check(a, state, b)
-        }))
-        .layer(axum::middleware::from_fn(web::latency));
+        .route("/images/scrape", scrape_route.clone())
+        .route("/api/v1/scrape", scrape_route)
+        .route("/api/v2/scrape", scrape_v2_route)
+        .route("/images/scrape/events", events_route)
+        .route("/openapi.json", get(web::openapi))
+        .route("/jobs", post(web::submit_job))
+        .route("/jobs/:id", get(web::job_status));
+    let app = app.layer(axum::middleware::from_fn(move |a, b| {
+        let state = astate.clone();
+        web::origin_check(a, state, b)
+    }));
+    // images are embedded by browsers without an origin, the signature protects them instead
+    let app = if config.thumbnail_key.is_some() {
+        app.route("/thumbnail/:digest/:url", get(web::thumbnail))
+    } else {
+        app
+    };
+    let app = if config.enable_proxy && config.camo_key.is_some() {
+        app.route("/proxy/:digest", get(web::proxy))
+            .route("/proxy/:digest/:url", get(web::proxy_hex))
+    } else {
+        app
+    };
+    let app = app.layer(axum::middleware::from_fn(web::latency));
     let app = match config.sentry_url {
         None => app,
         Some(ref _v) => app
@@ -236,6 +417,60 @@ async fn main_start() -> Result<()> {
 
 #[cfg(test)]
 mod test {
-  #[test]
-  fn test_dummy() {}
+    use super::*;
+
+    #[test]
+    fn test_dummy() {}
+
+    #[test]
+    fn test_origin_matches() {
+        assert!(origin_matches("*", "https://example.com"));
+        assert!(origin_matches("localhost:8080", "http://localhost:8080"));
+        assert!(!origin_matches("localhost", "http://localhost:8080"));
+        assert!(origin_matches(
+            "https://derpibooru.org",
+            "https://derpibooru.org"
+        ));
+        assert!(!origin_matches(
+            "https://derpibooru.org",
+            "http://derpibooru.org"
+        ));
+        assert!(origin_matches(
+            "*.derpibooru.org",
+            "https://www.derpibooru.org"
+        ));
+        assert!(origin_matches(
+            "*.derpibooru.org",
+            "https://a.b.derpibooru.org"
+        ));
+        assert!(!origin_matches(
+            "*.derpibooru.org",
+            "https://derpibooru.org"
+        ));
+        assert!(!origin_matches(
+            "*.derpibooru.org",
+            "https://evilderpibooru.org"
+        ));
+        assert!(origin_matches(
+            "https://*.derpibooru.org",
+            "https://www.derpibooru.org"
+        ));
+        assert!(!origin_matches(
+            "https://*.derpibooru.org",
+            "http://www.derpibooru.org"
+        ));
+    }
+
+    #[test]
+    fn test_allowed_origin() -> Result<()> {
+        let state = State::new(Configuration {
+            allowed_origins: "*.derpibooru.org,localhost:8080".to_string(),
+            ..Default::default()
+        })?;
+        assert!(state.is_allowed_origin(Some("https://www.derpibooru.org")));
+        assert!(state.is_allowed_origin(Some("http://localhost:8080")));
+        assert!(!state.is_allowed_origin(Some("https://example.com")));
+        assert!(!state.is_allowed_origin(None));
+        Ok(())
+    }
 }
@@ -0,0 +1,271 @@
+use crate::Configuration;
+use anyhow::{Context, Result};
+use axum::{body::Body, http, response::Response};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::time::Duration;
+use tracing::trace;
+use url::Url;
+
+type HmacSha1 = Hmac<Sha1>;
+
+const MAX_REDIRECTS: usize = 4;
+
+/// Longest wait for the upstream response headers or the next chunk of the body. There is
+/// no limit on the total duration, so large images can stream over slow connections.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upstream request headers passed on so browsers can revalidate their cached copy.
+const FORWARDED_REQUEST_HEADERS: &[&str] = &["if-none-match", "if-modified-since"];
+
+/// Upstream response headers passed on to the client.
+const FORWARDED_RESPONSE_HEADERS: &[&str] = &["etag", "last-modified", "expires"];
+
+/// Checks the digest of a proxy request as created by Camo and returns the URL it is for.
+#[tracing::instrument(skip(config))]
+pub fn verify_proxy_url(config: &Configuration, digest: &str, url: &str) -> Result<Url> {
+    let key = config
+        .camo_key
+        .as_ref()
+        .context("proxy is not configured")?;
+    let digest = hex::decode(digest).context("digest is not valid hex")?;
+    let mut mac = HmacSha1::new_from_slice(key.as_bytes())?;
+    mac.update(url.as_bytes());
+    mac.verify_slice(&digest)
+        .map_err(|_| anyhow::format_err!("digest does not match"))?;
+    Url::parse(url).context("url is not valid")
+}
+
+/// Decodes the hex encoded URL of a `/proxy/<digest>/<hex url>` request.
+pub fn decode_hex_url(hex_url: &str) -> Result<String> {
+    String::from_utf8(hex::decode(hex_url).context("url is not valid hex")?)
+        .context("url is not valid utf-8")
+}
+
+/// Whether the proxy serves upstream responses of this content type.
+///
+/// SVG is refused since it can carry scripts.
+fn allowed_content_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("image/") && mime != "image/svg+xml"
+}
+
+/// Fetches `url` from a public address and streams it back, following redirects.
+#[tracing::instrument(skip(config, headers))]
+pub async fn proxy_image(
+    config: &Configuration,
+    url: &Url,
+    headers: &http::HeaderMap,
+) -> Result<Response<Body>> {
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        if crate::camo::is_denied(config, &url) {
+            anyhow::bail!("refusing to proxy image from denied host");
+        }
+        let client = crate::scraper::safe_streaming_client(config, &url).await?;
+        let mut req = client.get(url.clone()).header("accept", "image/*");
+        for name in FORWARDED_REQUEST_HEADERS {
+            if let Some(value) = headers.get(*name) {
+                req = req.header(*name, value.as_bytes());
+            }
+        }
+        let resp = tokio::time::timeout(READ_TIMEOUT, req.send())
+            .await
+            .context("upstream request timed out")?
+            .context("upstream request failed")?;
+        if resp.status().is_redirection() && resp.status().as_u16() != 304 {
+            let location = resp
+                .headers()
+                .get("location")
+                .context("redirect without location")?
+                .to_str()
+                .context("redirect location invalid")?;
+            url = url.join(location).context("redirect location invalid")?;
+            trace!("following redirect to {}", url);
+            continue;
+        }
+        return stream_response(config, resp);
+    }
+    anyhow::bail!("too many redirects")
+}
+
+fn stream_response(config: &Configuration, resp: reqwest::Response) -> Result<Response<Body>> {
+    let mut builder = Response::builder()
+        .header(http::header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(
+            http::header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; img-src data:; style-src 'unsafe-inline'",
+        );
+    for name in FORWARDED_RESPONSE_HEADERS {
+        if let Some(value) = resp.headers().get(*name) {
+            builder = builder.header(*name, value.as_bytes());
+        }
+    }
+    let cache_control = resp
+        .headers()
+        .get("cache-control")
+        .map(|x| x.as_bytes().to_vec())
+        .unwrap_or_else(|| b"public, max-age=31536000".to_vec());
+    builder = builder.header(http::header::CACHE_CONTROL, cache_control);
+    if resp.status().as_u16() == 304 {
+        return Ok(builder
+            .status(http::StatusCode::NOT_MODIFIED)
+            .body(Body::empty())?);
+    }
+    if !resp.status().is_success() {
+        anyhow::bail!("upstream returned {}", resp.status());
+    }
+    let content_type = resp
+        .headers()
+        .get("content-type")
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if !allowed_content_type(&content_type) {
+        anyhow::bail!("upstream content type {:?} is not an image", content_type);
+    }
+    let max_bytes = config.proxy_max_bytes as u64;
+    if let Some(length) = resp.content_length() {
+        if length > max_bytes {
+            anyhow::bail!("upstream image exceeds {} bytes", max_bytes);
+        }
+        builder = builder.header(http::header::CONTENT_LENGTH, length);
+    }
+    // the state is `None` once the body ended with an error, which ends the stream
+    let body = futures::stream::unfold(Some((resp, 0u64)), move |state| async move {
+        let (mut resp, read) = state?;
+        let chunk = match tokio::time::timeout(READ_TIMEOUT, resp.chunk()).await {
+            Err(_) => Err(anyhow::format_err!("upstream read timed out")),
+            Ok(chunk) => chunk.map_err(anyhow::Error::from),
+        };
+        match chunk {
+            Ok(None) => None,
+            Ok(Some(chunk)) => match read.checked_add(chunk.len() as u64) {
+                Some(read) if read <= max_bytes => Some((Ok(chunk), Some((resp, read)))),
+                _ => Some((
+                    Err(anyhow::format_err!(
+                        "upstream image exceeds {} bytes",
+                        max_bytes
+                    )),
+                    None,
+                )),
+            },
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Ok(builder
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .body(Body::from_stream(body))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify_camo_urls() -> Result<()> {
+        let config = Configuration {
+            camo_key: Some("secret".to_string()),
+            camo_host: Some("https://scraper.example.com/proxy".to_string()),
+            ..Default::default()
+        };
+        let url = Url::parse("https://example.com/image.png")?;
+        let camo = crate::camo::camo_url(&config, &url)?;
+        let digest = camo
+            .path_segments()
+            .unwrap()
+            .next_back()
+            .unwrap()
+            .to_string();
+        let query_url = camo
+            .query_pairs()
+            .find(|(k, _)| k == "url")
+            .map(|(_, v)| v.to_string())
+            .unwrap();
+        assert_eq!(url, verify_proxy_url(&config, &digest, &query_url)?);
+        let hex_url = decode_hex_url(&hex::encode(url.as_str()))?;
+        assert_eq!(url, verify_proxy_url(&config, &digest, &hex_url)?);
+        assert!(verify_proxy_url(&config, &digest, "https://example.com/other.png").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_response_limit() -> Result<()> {
+        use futures::StreamExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        tokio_test::block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            let url = format!("http://{}/a.png", listener.local_addr()?);
+            let server = tokio::spawn(async move {
+                let (mut socket, _) = listener.accept().await?;
+                let mut request = [0u8; 1024];
+                let _ = socket.read(&mut request).await?;
+                // no content length, the limit has to be enforced while streaming
+                socket
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-type: image/png\r\nconnection: close\r\n\r\n",
+                    )
+                    .await?;
+                for _ in 0..3 {
+                    socket.write_all(&[0u8; 1000]).await?;
+                    socket.flush().await?;
+                }
+                anyhow::Ok(())
+            });
+            let config = Configuration {
+                proxy_max_bytes: 1500,
+                ..Default::default()
+            };
+            let resp = reqwest::get(&url).await?;
+            let mut body = stream_response(&config, resp)?
+                .into_body()
+                .into_data_stream();
+            let mut read = 0;
+            let mut failed = false;
+            while let Some(chunk) = body.next().await {
+                match chunk {
+                    Ok(chunk) => read += chunk.len(),
+                    Err(_) => {
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            assert!(failed);
+            assert!(read <= 1500);
+            // polling again after the error ends the stream instead of reading on
+            assert!(body.next().await.is_none());
+            // the server may see the connection go away while writing, that's fine
+            let _ = server.await;
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_denied_host() -> Result<()> {
+        let config = Configuration {
+            denied_image_hosts: "example.com".to_string(),
+            ..Default::default()
+        };
+        let url = Url::parse("https://cdn.example.com/image.png")?;
+        let r = tokio_test::block_on(proxy_image(&config, &url, &http::HeaderMap::new()));
+        let err = r.expect_err("denied host was proxied");
+        assert!(err.to_string().contains("denied host"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_content_type() {
+        assert!(allowed_content_type("image/png"));
+        assert!(allowed_content_type("Image/JPEG; charset=binary"));
+        assert!(!allowed_content_type("image/svg+xml"));
+        assert!(!allowed_content_type("text/html"));
+        assert!(!allowed_content_type(""));
+    }
+}
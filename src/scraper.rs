@@ -1,8 +1,13 @@
 mod buzzly;
+mod description;
 mod deviantart;
+mod hash;
 mod nitter;
 mod philomena;
+mod probe;
+mod rank;
 mod raw;
+mod tags;
 mod tumblr;
 mod twitter;
 mod twitterv2;
@@ -13,20 +18,24 @@ use anyhow::{Context, Result};
 use itertools::Itertools;
 use sentry::integrations::anyhow::capture_anyhow;
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{debug, trace};
 use url::Url;
 
 use crate::{Configuration, State};
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub use tags::TagRules;
+pub use tumblr::{TumblrAuth, TumblrResolver};
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default, utoipa::ToSchema)]
 #[serde(untagged)]
 pub enum ScrapeResult {
     Err(ScrapeResultError),
-    Ok(ScrapeResultData),
+    Ok(Box<ScrapeResultData>),
+    #[default]
     None,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, utoipa::ToSchema)]
 pub struct ScrapeResultError {
     errors: Vec<String>,
 }
@@ -37,22 +46,113 @@ impl From<String> for ScrapeResultError {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default, utoipa::ToSchema)]
 pub struct ScrapeResultData {
     source_url: Option<Url>,
     author_name: Option<String>,
     additional_tags: Option<Vec<String>>,
     description: Option<String>,
     images: Vec<ScrapeImage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    published_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_rating: Option<ContentRating>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author_url: Option<Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author_display_name: Option<String>,
+    /// All artists of the post, for collaborations and reposts. `author_name` is the first.
+    #[serde(default)]
+    authors: Vec<Author>,
+    /// All known sources of the post. `source_url` is the first.
+    #[serde(default)]
+    source_urls: Vec<Url>,
+    /// Upstream tags no rule of `TAG_RULES_FILE` matched.
+    #[serde(default)]
+    unmapped_tags: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct Author {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+}
+
+impl Author {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            url: None,
+            display_name: None,
+        }
+    }
 }
 
-impl Default for ScrapeResult {
-    fn default() -> Self {
-        Self::None
+impl ScrapeResultData {
+    /// Fills the plural author and source fields from the singular ones for scrapers that
+    /// only know a single author and source.
+    fn fill_authors_and_sources(&mut self) {
+        if self.authors.is_empty() {
+            if let Some(name) = &self.author_name {
+                self.authors.push(Author {
+                    name: name.clone(),
+                    url: self.author_url.clone(),
+                    display_name: self.author_display_name.clone(),
+                });
+            }
+        }
+        if self.source_urls.is_empty() {
+            self.source_urls.extend(self.source_url.clone());
+        }
+    }
+}
+
+/// Content rating of a post as signalled by upstream.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentRating {
+    Safe,
+    Questionable,
+    Explicit,
+}
+
+impl ContentRating {
+    /// Rating for upstreams that only flag posts as sensitive or mature.
+    fn from_sensitive_flag(sensitive: bool) -> Self {
+        if sensitive {
+            Self::Questionable
+        } else {
+            Self::Safe
+        }
     }
 }
 
 impl ScrapeResult {
+    /// Drops everything but the URLs from images, for comparing against expected results.
+    #[cfg(test)]
+    pub fn without_image_metadata(self) -> Self {
+        match self {
+            ScrapeResult::Ok(mut data) => {
+                data.images = data
+                    .images
+                    .into_iter()
+                    .map(|x| {
+                        let mut image = ScrapeImage::new(x.url, x.camo_url);
+                        image.media_type = x.media_type;
+                        image
+                    })
+                    .collect();
+                ScrapeResult::Ok(data)
+            }
+            v => v,
+        }
+    }
+
     pub fn from_err(e: Arc<anyhow::Error>) -> ScrapeResult {
         ScrapeResult::Err(ScrapeResultError {
             errors: {
@@ -70,10 +170,94 @@ impl ScrapeResult {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+/// Parses an upstream timestamp in the given `strftime` format, which must include an offset.
+fn parse_date(date: &str, format: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::parse_from_str(date.trim(), format)
+        .ok()
+        .map(|x| x.with_timezone(&chrono::Utc))
+}
+
+/// Kind of media an image URL points at.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaType {
+    #[default]
+    Image,
+    /// A video, including GIFs that upstream only serves as video. `camo_url` then points at
+    /// a still poster frame if upstream has one.
+    Video,
+}
+
+/// Video formats that can be imported, unlike streaming playlists such as HLS.
+const VIDEO_MIME_TYPES: &[&str] = &["video/mp4", "video/webm"];
+
+/// MIME type of a video that can be imported, taken from the declared content type or, if
+/// there is none, from the extension of the URL.
+pub fn video_mime_type(content_type: Option<&str>, url: &Url) -> Option<&'static str> {
+    match content_type {
+        Some(content_type) => {
+            let mime = content_type.split(';').next().unwrap_or_default().trim();
+            VIDEO_MIME_TYPES
+                .iter()
+                .find(|x| x.eq_ignore_ascii_case(mime))
+                .copied()
+        }
+        None => {
+            let path = url.path().to_ascii_lowercase();
+            if path.ends_with(".mp4") {
+                Some("video/mp4")
+            } else if path.ends_with(".webm") {
+                Some("video/webm")
+            } else {
+                None
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, utoipa::ToSchema)]
 pub struct ScrapeImage {
     url: Url,
     camo_url: Url,
+    #[serde(default)]
+    media_type: MediaType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mime_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    animated: Option<bool>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    preferred: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha512: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    phash: Option<String>,
+}
+
+impl ScrapeImage {
+    pub fn new(url: Url, camo_url: Url) -> Self {
+        Self {
+            url,
+            camo_url,
+            media_type: MediaType::Image,
+            width: None,
+            height: None,
+            mime_type: None,
+            size_bytes: None,
+            animated: None,
+            preferred: false,
+            sha256: None,
+            sha512: None,
+            phash: None,
+        }
+    }
 }
 
 impl std::fmt::Debug for ScrapeImage {
@@ -81,10 +265,66 @@ impl std::fmt::Debug for ScrapeImage {
         f.debug_struct("ScrapeImage")
             .field("url", &self.url.to_string())
             .field("camo_url", &self.camo_url.to_string())
+            .field("media_type", &self.media_type)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("mime_type", &self.mime_type)
+            .field("size_bytes", &self.size_bytes)
+            .field("animated", &self.animated)
+            .field("preferred", &self.preferred)
+            .field("sha256", &self.sha256)
+            .field("sha512", &self.sha512)
+            .field("phash", &self.phash)
             .finish()
     }
 }
 
+/// A stage of a running scrape, reported to listeners of [`scrape_with_progress`].
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum ScrapeProgress {
+    ScraperSelected { scraper: String },
+    MetadataFetched,
+    ImageResolved { image: Box<ScrapeImage> },
+}
+
+impl ScrapeProgress {
+    pub fn stage(&self) -> &'static str {
+        match self {
+            ScrapeProgress::ScraperSelected { .. } => "scraper_selected",
+            ScrapeProgress::MetadataFetched => "metadata_fetched",
+            ScrapeProgress::ImageResolved { .. } => "image_resolved",
+        }
+    }
+}
+
+/// Listener of the progress of a scrape, along with the configuration of the scrape.
+struct ProgressSink {
+    tx: tokio::sync::mpsc::UnboundedSender<ScrapeProgress>,
+    config: Configuration,
+}
+
+tokio::task_local! {
+    static PROGRESS: ProgressSink;
+}
+
+/// Reports progress of the current scrape, if anyone is listening.
+///
+/// Images on denied hosts are never reported, they are dropped from the result later on.
+pub fn report_progress(progress: ScrapeProgress) {
+    let _ = PROGRESS.try_with(|sink| {
+        if let ScrapeProgress::ImageResolved { image } = &progress {
+            if crate::camo::is_denied(&sink.config, &image.url) {
+                trace!("not reporting image {} from denied host", image.url);
+                return;
+            }
+        }
+        if sink.tx.send(progress).is_err() {
+            trace!("progress listener went away");
+        }
+    });
+}
+
 #[tracing::instrument(skip(config))]
 pub fn client(config: &Configuration) -> Result<reqwest_middleware::ClientWithMiddleware> {
     client_with_redir_limit(config, reqwest::redirect::Policy::none())
@@ -95,12 +335,147 @@ pub fn client_with_redir_limit(
     config: &Configuration,
     redir_policy: reqwest::redirect::Policy,
 ) -> Result<reqwest_middleware::ClientWithMiddleware> {
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_millis(5000))
+    build_client(client_builder(config, redir_policy)?)
+}
+
+/// Creates a client for fetching `url` on behalf of a user, which refuses to connect to
+/// loopback, private or otherwise non-public addresses.
+///
+/// The host is resolved up front and the client is pinned to the checked addresses, so a
+/// second lookup can't be used to sneak in an internal address. For the same reason it never
+/// goes through `HTTP_PROXY`: the proxy would resolve the host itself.
+#[tracing::instrument(skip(config))]
+pub async fn safe_client(
+    config: &Configuration,
+    url: &Url,
+) -> Result<reqwest_middleware::ClientWithMiddleware> {
+    build_client(
+        safe_client_builder(config, url)
+            .await?
+            .timeout(REQUEST_TIMEOUT),
+    )
+}
+
+/// Like [`safe_client`], but without a limit on the total duration of a request, for
+/// streaming large responses. Callers must time out reads themselves.
+#[tracing::instrument(skip(config))]
+pub async fn safe_streaming_client(
+    config: &Configuration,
+    url: &Url,
+) -> Result<reqwest_middleware::ClientWithMiddleware> {
+    build_client(safe_client_builder(config, url).await?)
+}
+
+async fn safe_client_builder(config: &Configuration, url: &Url) -> Result<reqwest::ClientBuilder> {
+    let addrs = public_addrs(url).await?;
+    let builder = base_client_builder(config, reqwest::redirect::Policy::none()).no_proxy();
+    Ok(match url.host() {
+        Some(url::Host::Domain(domain)) => builder.resolve_to_addrs(domain, &addrs),
+        _ => builder,
+    })
+}
+
+/// Fails unless `url` is an http(s) URL whose host only resolves to public addresses.
+#[tracing::instrument]
+pub async fn ensure_public_url(url: &Url) -> Result<()> {
+    public_addrs(url).await.map(|_| ())
+}
+
+/// Checks the host of `url` is public, returning the addresses a domain resolved to.
+async fn public_addrs(url: &Url) -> Result<Vec<std::net::SocketAddr>> {
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("refusing to fetch non-http URL");
+    }
+    let port = url
+        .port_or_known_default()
+        .context("URL has no known port")?;
+    match url.host() {
+        None => anyhow::bail!("URL has no host"),
+        Some(url::Host::Ipv4(ip)) => {
+            ensure_public(ip.into())?;
+            Ok(Vec::new())
+        }
+        Some(url::Host::Ipv6(ip)) => {
+            ensure_public(ip.into())?;
+            Ok(Vec::new())
+        }
+        Some(url::Host::Domain(domain)) => {
+            let addrs: Vec<std::net::SocketAddr> = tokio::net::lookup_host((domain, port))
+                .await
+                .context("could not resolve host")?
+                .collect();
+            if addrs.is_empty() {
+                anyhow::bail!("host did not resolve to any address");
+            }
+            for addr in &addrs {
+                ensure_public(addr.ip())?;
+            }
+            Ok(addrs)
+        }
+    }
+}
+
+fn ensure_public(ip: std::net::IpAddr) -> Result<()> {
+    if is_public_ip(ip) {
+        Ok(())
+    } else {
+        anyhow::bail!("refusing to connect to non-public address {}", ip)
+    }
+}
+
+fn is_public_ip(ip: std::net::IpAddr) -> bool {
+    use std::net::IpAddr;
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // shared address space, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64)
+                // reserved, 240.0.0.0/4
+                || a >= 240
+                || a == 0)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // link local, fe80::/10
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Limit on the total duration of a request, including reading the response.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(5000);
+
+fn base_client_builder(
+    config: &Configuration,
+    redir_policy: reqwest::redirect::Policy,
+) -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
         .connect_timeout(std::time::Duration::from_millis(2500))
         .user_agent(&config.user_agent)
         .cookie_store(true)
-        .redirect(redir_policy);
+        .redirect(redir_policy)
+}
+
+fn client_builder(
+    config: &Configuration,
+    redir_policy: reqwest::redirect::Policy,
+) -> Result<reqwest::ClientBuilder> {
+    let client = base_client_builder(config, redir_policy).timeout(REQUEST_TIMEOUT);
     let client = match config.proxy_url.clone() {
         None => client,
         Some(proxy_url) => {
@@ -119,12 +494,63 @@ pub fn client_with_redir_limit(
             client.proxy(proxy)
         }
     };
+    Ok(client)
+}
+
+fn build_client(
+    client: reqwest::ClientBuilder,
+) -> Result<reqwest_middleware::ClientWithMiddleware> {
     Ok(reqwest_middleware::ClientBuilder::new(client.build()?)
         .with(reqwest_tracing::TracingMiddleware::default())
         .build())
     //Ok(client.build()?)
 }
 
+/// Downloads the body of `url`, failing if it is larger than `max_bytes`.
+#[tracing::instrument(skip(client))]
+pub async fn fetch_bytes(
+    client: &reqwest_middleware::ClientWithMiddleware,
+    url: &Url,
+    max_bytes: usize,
+) -> Result<Vec<u8>> {
+    let mut resp = client
+        .get(url.clone())
+        .send()
+        .await
+        .context("download request failed")?
+        .error_for_status()
+        .context("download returned error code")?;
+    if resp.content_length().unwrap_or_default() > max_bytes as u64 {
+        anyhow::bail!("download exceeds {} bytes", max_bytes);
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = resp.chunk().await.context("could not read download")? {
+        if data.len() + chunk.len() > max_bytes {
+            anyhow::bail!("download exceeds {} bytes", max_bytes);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Largest width or height of a downloaded image that is decoded.
+const MAX_IMAGE_DIMENSION: u32 = 16384;
+/// Most memory the decoder may allocate, so decompression bombs fail instead of exhausting it.
+const MAX_IMAGE_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// Decodes a downloaded image within limits on its dimensions and the memory used.
+pub fn decode_image(data: &[u8]) -> Result<image::DynamicImage> {
+    let mut reader = image::ImageReader::new(std::io::Cursor::new(data))
+        .with_guessed_format()
+        .context("could not read image")?;
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_IMAGE_ALLOC);
+    reader.limits(limits);
+    reader.decode().context("could not decode image")
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 enum Scraper {
     Twitter,
@@ -136,9 +562,9 @@ enum Scraper {
     Raw,
 }
 
-impl ToString for Scraper {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for Scraper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             Scraper::Twitter => "twitter",
             Scraper::Nitter => "nitter",
             Scraper::Tumblr => "tumblr",
@@ -146,8 +572,7 @@ impl ToString for Scraper {
             Scraper::Philomena => "philomena",
             Scraper::Buzzly => "buzzly",
             Scraper::Raw => "raw",
-        }
-        .to_string()
+        })
     }
 }
 
@@ -159,40 +584,42 @@ impl Scraper {
         url: &url::Url,
     ) -> Result<Option<Self>> {
         use futures::future::FutureExt;
-        let (r0, r1, r2, r3, r4, r5) =
-            tokio::try_join!(
-                twitter::is_twitter(url).map(|matf| matf.map(|mat| if mat {
-                    Some(Self::Twitter)
-                } else {
-                    None
-                })),
-                nitter::is_nitter(url).map(|matf| matf.map(|mat| if mat {
-                    Some(Self::Nitter)
+        let (r0, r1, r2, r3, r4, r5) = tokio::try_join!(
+            twitter::is_twitter(url).map(|matf| matf.map(|mat| if mat {
+                Some(Self::Twitter)
+            } else {
+                None
+            })),
+            nitter::is_nitter(url).map(|matf| matf.map(|mat| if mat {
+                Some(Self::Nitter)
+            } else {
+                None
+            })),
+            tumblr::is_tumblr(config, state, url).map(|matf| matf.map(|mat| if mat {
+                Some(Self::Tumblr)
+            } else {
+                None
+            })),
+            deviantart::is_deviantart(url).map(|matf| matf.map(|mat| {
+                if mat {
+                    Some(Self::DeviantArt)
                 } else {
                     None
-                })),
-                tumblr::is_tumblr(state.tumblr_dns_cache.clone(), url)
-                    .map(|matf| matf.map(|mat| if mat { Some(Self::Tumblr) } else { None })),
-                deviantart::is_deviantart(url).map(|matf| matf.map(|mat| {
-                    if mat {
-                        Some(Self::DeviantArt)
-                    } else {
-                        None
-                    }
-                })),
-                philomena::is_philomena(url).map(|matf| matf.map(|mat| {
-                    if mat {
-                        Some(Self::Philomena)
-                    } else {
-                        None
-                    }
-                })),
-                buzzly::is_buzzlyart(url).map(|matf| matf.map(|mat| if mat {
-                    Some(Self::Buzzly)
+                }
+            })),
+            philomena::is_philomena(url).map(|matf| matf.map(|mat| {
+                if mat {
+                    Some(Self::Philomena)
                 } else {
                     None
-                })),
-            )?;
+                }
+            })),
+            buzzly::is_buzzlyart(url).map(|matf| matf.map(|mat| if mat {
+                Some(Self::Buzzly)
+            } else {
+                None
+            })),
+        )?;
         let res = vec![r0, r1, r2, r3, r4, r5];
         let res: Vec<Scraper> = res.into_iter().flatten().collect_vec();
         Ok(if res.is_empty() {
@@ -213,10 +640,11 @@ impl Scraper {
         })
     }
 
-    #[tracing::instrument(skip(config), fields(self))]
+    #[tracing::instrument(skip(config, state), fields(self))]
     async fn execute_scrape(
         self,
         config: &Configuration,
+        state: &State,
         url: &url::Url,
     ) -> Result<Option<ScrapeResult>> {
         sentry::configure_scope(|scope| {
@@ -232,9 +660,14 @@ impl Scraper {
             Scraper::Nitter => Ok(nitter::nitter_scrape(config, url)
                 .await
                 .context("Nitter parser failed")?),
-            Scraper::Tumblr => Ok(tumblr::tumblr_scrape(config, url)
-                .await
-                .context("Tumblr parser failed")?),
+            Scraper::Tumblr => Ok(tumblr::tumblr_scrape(
+                config,
+                state.tumblr_upsize_cache.clone(),
+                state.tumblr_auth.clone(),
+                url,
+            )
+            .await
+            .context("Tumblr parser failed")?),
             Scraper::DeviantArt => Ok(deviantart::deviantart_scrape(config, url)
                 .await
                 .context("DeviantArt parser failed")?),
@@ -260,15 +693,90 @@ pub async fn scrape(
     use std::str::FromStr;
     let url = url::Url::from_str(url).context("could not parse URL for scraper")?;
     let check = Scraper::get_scraper(config, state, &url).await;
-    let check = check.map_err(|e| {
-        capture_anyhow(&e);
-        e
+    let check = check.inspect_err(|e| {
+        capture_anyhow(e);
     })?;
-    match check {
-        Some(scraper) => scraper.execute_scrape(config, &url).await.map_err(|e| {
-            capture_anyhow(&e);
-            e
-        }),
-        None => Ok(None),
+    if let Some(scraper) = check {
+        report_progress(ScrapeProgress::ScraperSelected {
+            scraper: scraper.to_string(),
+        });
     }
+    let res = match check {
+        Some(scraper) => scraper
+            .execute_scrape(config, state, &url)
+            .await
+            .inspect_err(|e| {
+                capture_anyhow(e);
+            })?,
+        None => return Ok(None),
+    };
+    match res {
+        Some(ScrapeResult::Ok(mut data)) => {
+            data.fill_authors_and_sources();
+            state.tag_rules.apply(&mut data);
+            data.description =
+                description::normalize_description(config, data.description.take()).await;
+            data.images.retain(|image| {
+                let denied = crate::camo::is_denied(config, &image.url);
+                if denied {
+                    debug!("dropping image {} from denied host", image.url);
+                }
+                !denied
+            });
+            if config.probe_images {
+                probe_images(config, &mut data.images).await;
+            }
+            data.images = rank::rank_images(data.images);
+            if config.hash_images {
+                hash_images(config, &mut data.images).await;
+            }
+            Ok(Some(ScrapeResult::Ok(data)))
+        }
+        res => Ok(res),
+    }
+}
+
+/// Downloads and hashes all images, so the booru can check for duplicates before upload.
+#[tracing::instrument(skip(config, images))]
+async fn hash_images(config: &Configuration, images: &mut [ScrapeImage]) {
+    futures::future::join_all(images.iter_mut().map(|image| async move {
+        let res = match safe_client(config, &image.url).await {
+            Ok(client) => hash::hash_image(&client, image, config.hash_max_bytes).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            debug!("could not hash image {}: {:?}", image.url, e);
+        }
+    }))
+    .await;
+}
+
+/// Fills in dimensions, type and size of images the scraper couldn't get from upstream.
+#[tracing::instrument(skip(config, images))]
+async fn probe_images(config: &Configuration, images: &mut [ScrapeImage]) {
+    futures::future::join_all(images.iter_mut().map(|image| async move {
+        let res = match safe_client(config, &image.url).await {
+            Ok(client) => probe::probe_image(&client, image, config.probe_max_bytes).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            debug!("could not probe image {}: {:?}", image.url, e);
+        }
+    }))
+    .await;
+}
+
+/// Like [`scrape`], but sends the stages of the scrape to `progress` as they happen.
+#[tracing::instrument(skip(config, state, progress))]
+pub async fn scrape_with_progress(
+    config: &Configuration,
+    state: &State,
+    url: &str,
+    progress: tokio::sync::mpsc::UnboundedSender<ScrapeProgress>,
+) -> Result<Option<ScrapeResult>> {
+    let sink = ProgressSink {
+        tx: progress,
+        config: config.clone(),
+    };
+    PROGRESS.scope(sink, scrape(config, state, url)).await
 }
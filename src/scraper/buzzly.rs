@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 use std::str::FromStr;
 
 use anyhow::Result;
@@ -9,7 +8,9 @@ use reqwest_middleware::ClientWithMiddleware as Client;
 use tracing::*;
 
 use crate::camo::camo_url;
-use crate::scraper::{ScrapeImage, ScrapeResult, ScrapeResultData};
+use crate::scraper::{
+    report_progress, ScrapeImage, ScrapeProgress, ScrapeResult, ScrapeResultData,
+};
 use crate::Configuration;
 
 lazy_static::lazy_static! {
@@ -40,13 +41,6 @@ pub async fn make_buzzly_doc_request(
     slug: &str,
     username: &str,
 ) -> Result<get_submission::ResponseData> {
-    #[derive(serde::Serialize)]
-    struct Query {
-        #[serde(rename = "operationName")]
-        operation_name: String,
-        query: String,
-        variables: HashMap<String, String>,
-    }
     let vars = get_submission::Variables {
         slug: slug.to_string(),
         username: username.to_string(),
@@ -87,6 +81,7 @@ pub async fn buzzlyart_scrape(config: &Configuration, url: &Url) -> Result<Optio
         .as_ref()
         .ok_or_else(|| anyhow::format_err!("missing account metadata"))?;
     trace!("got data: {account:?} {submission:?}");
+    report_progress(ScrapeProgress::MetadataFetched);
     let author_name = account.username.clone();
     let description = submission.description;
     let url = submission
@@ -102,16 +97,27 @@ pub async fn buzzlyart_scrape(config: &Configuration, url: &Url) -> Result<Optio
         .ok_or_else(|| anyhow::format_err!("missing tags fields"))?;
     let mut tags: Vec<String> = tags.into_iter().flatten().collect();
     tags.push(format!("artist:{author_name}"));
-    Ok(Some(ScrapeResult::Ok(ScrapeResultData {
+    let image = ScrapeImage::new(url, camo_url(config, &camod_url)?);
+    report_progress(ScrapeProgress::ImageResolved {
+        image: Box::new(image.clone()),
+    });
+    Ok(Some(ScrapeResult::Ok(Box::new(ScrapeResultData {
         source_url: Some(origin_url.clone()),
-        author_name: Some(author_name),
+        author_name: Some(author_name.clone()),
         additional_tags: Some(tags),
-        description: Some(description),
-        images: vec![ScrapeImage {
-            url,
-            camo_url: camo_url(config, &camod_url)?,
-        }],
-    })))
+        description: Some(super::description::html_to_markdown(&description)?),
+        images: vec![image],
+        title: None,
+        published_at: None,
+        content_rating: None,
+        author_url: Some(Url::from_str(&format!(
+            "https://buzzly.art/~{author_name}"
+        ))?),
+        author_display_name: Some(account.display_name.clone()),
+        authors: Vec::new(),
+        source_urls: Vec::new(),
+        unmapped_tags: Vec::new(),
+    }))))
 }
 
 #[cfg(test)]
@@ -130,9 +136,16 @@ mod test {
         let url = r#"https://buzzly.art/~mothnmag/art/fizzy"#;
         let config = Configuration::default();
         let state = State::new(config.clone())?;
-        let scrape = tokio_test::block_on(scrape(&config, &state, url))?.unwrap();
+        let mut scrape = tokio_test::block_on(scrape(&config, &state, url))?
+            .unwrap()
+            .without_image_metadata();
+        if let ScrapeResult::Ok(data) = &mut scrape {
+            // the display name can be changed by the artist, only check it is there
+            assert!(data.author_display_name.take().is_some());
+            data.authors.iter_mut().for_each(|x| x.display_name = None);
+        }
 
-        assert_eq!(ScrapeResult::Ok(ScrapeResultData{
+        let mut expected = ScrapeResultData{
             source_url: Some(
                 Url::parse("https://buzzly.art/~mothnmag/art/fizzy").unwrap(),
             ),
@@ -149,15 +162,16 @@ mod test {
                 ],
             ),
             description: Some(
-                "<p>AHH sorry i havent posted in a while work has been so busy h</p><p>but!! heres some fizzy art for oskar :3</p>".to_string(),
+                "AHH sorry i havent posted in a while work has been so busy h\n\nbut!! heres some fizzy art for oskar :3".to_string(),
             ),
             images: vec![
-                ScrapeImage {
-                    url: Url::parse("https://submissions.buzzly.art/IMAGE/542f4f12-a882-4899-b37e-e4fd0e1765d4_055d6284-907c-4f84-a99b-2502201f4100.png").unwrap(),
-                    camo_url: Url::parse("https://submissions.buzzly.art/IMAGE/542f4f12-a882-4899-b37e-e4fd0e1765d4_67a9175f-04c3-4401-961a-670cc10c6a08_thumbnail.webp").unwrap(),
-                },
+                ScrapeImage::new(Url::parse("https://submissions.buzzly.art/IMAGE/542f4f12-a882-4899-b37e-e4fd0e1765d4_055d6284-907c-4f84-a99b-2502201f4100.png").unwrap(), Url::parse("https://submissions.buzzly.art/IMAGE/542f4f12-a882-4899-b37e-e4fd0e1765d4_67a9175f-04c3-4401-961a-670cc10c6a08_thumbnail.webp").unwrap()),
             ],
-        }), scrape);
+            author_url: Some(Url::parse("https://buzzly.art/~mothnmag")?),
+            ..Default::default()
+        };
+        expected.fill_authors_and_sources();
+        assert_eq!(ScrapeResult::Ok(Box::new(expected)), scrape);
 
         Ok(())
     }
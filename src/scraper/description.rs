@@ -0,0 +1,384 @@
+use crate::Configuration;
+use anyhow::Result;
+use htmlentity::entity::ICodedDataTrait;
+use tracing::{debug, trace};
+use url::Url;
+use visdom::types::{BoxDynElement, IEnumTyped};
+use visdom::Vis;
+
+lazy_static::lazy_static! {
+    static ref SHORT_LINK_REGEX: regex::Regex =
+        regex::Regex::new(r"https?://t\.co/[A-Za-z0-9]+")
+            .expect("failure in setting up essential regex");
+    static ref BLANK_LINES_REGEX: regex::Regex =
+        regex::Regex::new(r"\n{3,}").expect("failure in setting up essential regex");
+    static ref SPACES_REGEX: regex::Regex =
+        regex::Regex::new(r"[ \t\r\n]+").expect("failure in setting up essential regex");
+}
+
+/// Most short links resolved at the same time.
+const MAX_CONCURRENT_EXPANSIONS: usize = 4;
+
+/// Characters with a meaning in Philomena's Markdown.
+const MARKDOWN_SPECIAL: &[char] = &['\\', '*', '_', '~', '`', '[', ']', '|', '^', '%'];
+
+/// Escapes plain text so Philomena renders it as is, leaving URLs alone so they still link.
+pub fn text_to_markdown(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let line = line
+                .split(' ')
+                .map(|word| {
+                    if word.starts_with("http://") || word.starts_with("https://") {
+                        word.to_string()
+                    } else {
+                        escape(word)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            escape_block_start(line)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Escapes what would make a line a quote, heading or list item.
+fn escape_block_start(mut line: String) -> String {
+    let start = line.len() - line.trim_start().len();
+    let rest = &line[start..];
+    let marker = if rest.starts_with(['>', '#', '-', '+', '=']) {
+        Some(start)
+    } else {
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        (digits > 0 && rest[digits..].starts_with(['.', ')'])).then_some(start + digits)
+    };
+    if let Some(marker) = marker {
+        line.insert(marker, '\\');
+    }
+    line
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if MARKDOWN_SPECIAL.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Converts upstream HTML to Philomena Markdown, keeping links, emphasis and line breaks and
+/// dropping everything else.
+pub fn html_to_markdown(html: &str) -> Result<String> {
+    let root = Vis::load(format!("<div>{html}</div>"))
+        .map_err(|e| anyhow::format_err!("could not parse description: {}", e))?;
+    let mut out = String::new();
+    if let Some(root) = root.get(0) {
+        children_to_markdown(root, &mut out);
+    }
+    Ok(tidy(&out))
+}
+
+fn children_to_markdown(element: &BoxDynElement, out: &mut String) {
+    for node in element.child_nodes() {
+        match node.typed() {
+            IEnumTyped::Text(text) => {
+                let text = SPACES_REGEX
+                    .replace_all(&text.text_content(), " ")
+                    .to_string();
+                if out.is_empty() || out.ends_with('\n') {
+                    out.push_str(&escape(text.trim_start()));
+                } else {
+                    out.push_str(&escape(&text));
+                }
+            }
+            IEnumTyped::Element(element) => element_to_markdown(&element, out),
+            IEnumTyped::UncareNode(_) => (),
+        }
+    }
+}
+
+fn element_to_markdown(element: &BoxDynElement, out: &mut String) {
+    let tag = element.tag_name().to_ascii_lowercase();
+    match tag.as_str() {
+        "br" => out.push('\n'),
+        "script" | "style" | "img" | "video" | "audio" | "iframe" | "figure" => (),
+        "a" => {
+            let href = element
+                .get_attribute("href")
+                .and_then(|x| {
+                    htmlentity::entity::decode(x.to_string().as_bytes())
+                        .to_string()
+                        .ok()
+                })
+                .and_then(|x| Url::parse(&x).ok())
+                .filter(|x| matches!(x.scheme(), "http" | "https"));
+            let mut text = String::new();
+            children_to_markdown(element, &mut text);
+            let text = text.trim();
+            match href {
+                None => out.push_str(text),
+                Some(href) if text.is_empty() || text == escape(href.as_str()) => {
+                    out.push_str(href.as_str())
+                }
+                Some(href) => out.push_str(&format!("[{}]({})", text, href)),
+            }
+        }
+        "b" | "strong" => inline(element, "**", out),
+        "i" | "em" => inline(element, "*", out),
+        "s" | "del" | "strike" => inline(element, "~~", out),
+        "code" => inline(element, "`", out),
+        "li" => {
+            block_start(out, false);
+            out.push_str("* ");
+            children_to_markdown(element, out);
+            out.push('\n');
+        }
+        "blockquote" => {
+            let mut text = String::new();
+            children_to_markdown(element, &mut text);
+            block_start(out, true);
+            for line in tidy(&text).lines() {
+                out.push_str(&format!("> {}\n", line));
+            }
+            out.push('\n');
+        }
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let mut text = String::new();
+            children_to_markdown(element, &mut text);
+            block_start(out, true);
+            out.push_str(&format!("**{}**\n\n", text.trim()));
+        }
+        "p" | "div" | "ul" | "ol" | "section" | "article" | "header" | "footer" => {
+            block_start(out, true);
+            children_to_markdown(element, out);
+            out.push_str("\n\n");
+        }
+        _ => children_to_markdown(element, out),
+    }
+}
+
+fn inline(element: &BoxDynElement, marker: &str, out: &mut String) {
+    let mut text = String::new();
+    children_to_markdown(element, &mut text);
+    match text.trim() {
+        "" => out.push_str(&text),
+        trimmed => out.push_str(&format!("{marker}{trimmed}{marker}")),
+    }
+}
+
+/// Starts a new line, or a new paragraph if `paragraph` is set.
+fn block_start(out: &mut String, paragraph: bool) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    if paragraph && !out.is_empty() && !out.ends_with("\n\n") {
+        out.push('\n');
+    }
+}
+
+/// Strips trailing whitespace from lines and collapses runs of blank lines.
+fn tidy(text: &str) -> String {
+    let text = text
+        .lines()
+        .map(|x| x.trim_end())
+        .collect::<Vec<_>>()
+        .join("\n");
+    BLANK_LINES_REGEX
+        .replace_all(&text, "\n\n")
+        .trim()
+        .to_string()
+}
+
+/// Returns where the last `[text](url)` link of `text` starts if the text ends inside it.
+fn unclosed_link(text: &str) -> Option<usize> {
+    let mut open = None;
+    let mut in_url = false;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (c, open) {
+            ('\\', _) => {
+                chars.next();
+            }
+            ('[', None) => open = Some(i),
+            (']', Some(_)) if !in_url => match chars.peek() {
+                Some((_, '(')) => {
+                    chars.next();
+                    in_url = true;
+                }
+                _ => open = None,
+            },
+            (')', Some(_)) if in_url => {
+                open = None;
+                in_url = false;
+            }
+            _ => (),
+        }
+    }
+    open
+}
+
+/// Cuts the description down to `max_length` characters, ending it with an ellipsis.
+///
+/// A link that would be cut in half is dropped as a whole, so no broken Markdown is left.
+fn truncate(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+    let mut text: String = text.chars().take(max_length.saturating_sub(1)).collect();
+    if let Some(start) = unclosed_link(&text) {
+        text.truncate(start);
+    }
+    // a backslash whose escaped character was cut off would escape the ellipsis instead
+    let backslashes = text.len() - text.trim_end_matches('\\').len();
+    if backslashes % 2 == 1 {
+        text.pop();
+    }
+    text.truncate(text.trim_end().len());
+    text.push('…');
+    text
+}
+
+/// Looks up where a `t.co` link redirects to.
+async fn expand_short_link(config: &Configuration, link: &str) -> Result<Option<Url>> {
+    let link = Url::parse(link)?;
+    let client = crate::scraper::safe_client(config, &link).await?;
+    let resp = client.head(link).send().await?;
+    Ok(resp
+        .headers()
+        .get("location")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| Url::parse(x).ok()))
+}
+
+/// Replaces `t.co` links with the URL they redirect to.
+#[tracing::instrument(skip(config, text))]
+async fn expand_short_links(config: &Configuration, text: &str) -> String {
+    use futures::StreamExt;
+    let links: Vec<String> = SHORT_LINK_REGEX
+        .find_iter(text)
+        .map(|x| x.as_str().to_string())
+        .collect();
+    let expanded: Vec<(String, Url)> =
+        futures::stream::iter(itertools::Itertools::unique(links.into_iter()))
+            .map(|link| async move {
+                match expand_short_link(config, &link).await {
+                    Ok(location) => location.map(|x| (link, x)),
+                    Err(e) => {
+                        debug!("could not expand {}: {:?}", link, e);
+                        None
+                    }
+                }
+            })
+            .buffer_unordered(MAX_CONCURRENT_EXPANSIONS)
+            .filter_map(|x| async move { x })
+            .collect()
+            .await;
+    let mut text = text.to_string();
+    for (link, location) in expanded {
+        trace!("expanded {} to {}", link, location);
+        text = text.replace(&link, location.as_str());
+    }
+    text
+}
+
+/// Cleans up a scraped Markdown description: expands short links, strips surrounding
+/// whitespace and applies the configured maximum length.
+///
+/// Line ends are left alone, converters from HTML tidy them already and trailing spaces are
+/// hard line breaks in descriptions that are Markdown upstream.
+#[tracing::instrument(skip(config, description))]
+pub async fn normalize_description(
+    config: &Configuration,
+    description: Option<String>,
+) -> Option<String> {
+    let description = description?.trim().to_string();
+    let description = if config.expand_short_links {
+        expand_short_links(config, &description).await
+    } else {
+        description
+    };
+    let description = truncate(&description, config.description_max_length);
+    if description.is_empty() {
+        None
+    } else {
+        Some(description)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_html_to_markdown() -> Result<()> {
+        assert_eq!(
+            "AHH sorry i havent posted in a while\n\nbut!! heres some **fizzy** art :3",
+            html_to_markdown(
+                "<p>AHH sorry i havent posted in a while</p><p>but!! heres some <b>fizzy</b> art :3</p>"
+            )?
+        );
+        assert_eq!(
+            "line one\nline [two](https://example.com/a?b=c&d=e)\n\n* a\\_b\n* https://example.com/",
+            html_to_markdown(
+                "line one<br>line <a href=\"https://example.com/a?b=c&amp;d=e\">two</a>\
+                 <script>alert(1)</script><ul><li>a_b</li>\
+                 <li><a href=\"https://example.com/\">https://example.com/</a></li></ul>"
+            )?
+        );
+        assert_eq!(
+            "> quoted\n\nafter",
+            html_to_markdown("<blockquote><p>quoted</p></blockquote><p>after</p>")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_text_to_markdown() {
+        assert_eq!(
+            "50\\% off \\*today\\*\nhttps://example.com/a_b",
+            text_to_markdown("50% off *today*\nhttps://example.com/a_b")
+        );
+        assert_eq!(
+            "\\> not a quote\n\\# no heading\n  \\- no list\n12\\. March\n1\\) one\n1999 was good",
+            text_to_markdown(
+                "> not a quote\n# no heading\n  - no list\n12. March\n1) one\n1999 was good"
+            )
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!("short", truncate("short", 10));
+        assert_eq!("a long…", truncate("a long description", 8));
+        assert_eq!("ééé…", truncate("éééééé", 4));
+        assert_eq!(
+            "see…",
+            truncate("see [my gallery](https://example.com/gallery) too", 20)
+        );
+        assert_eq!(
+            "see [it](https://example.com/) and…",
+            truncate("see [it](https://example.com/) and more", 36)
+        );
+        assert_eq!("a \\[b…", truncate("a \\[b c d e f", 7));
+        // an escape pair is not split
+        assert_eq!("a…", truncate("a \\*b\\*", 4));
+        assert_eq!("a\\\\…", truncate("a\\\\\\*b", 5));
+    }
+
+    #[test]
+    fn test_normalize_keeps_hard_breaks() {
+        let config = Configuration::default();
+        let description = "line one  \nline two\n";
+        assert_eq!(
+            Some("line one  \nline two".to_string()),
+            tokio_test::block_on(normalize_description(
+                &config,
+                Some(description.to_string())
+            ))
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::scraper::client;
-use crate::scraper::ScrapeResultData;
+use crate::scraper::{report_progress, ContentRating, ScrapeProgress, ScrapeResultData};
 use crate::{
     scraper::{ScrapeImage, ScrapeResult},
     Configuration,
@@ -8,7 +8,7 @@ use anyhow::Context;
 use anyhow::Result;
 use regex::{Captures, Regex};
 use std::str::FromStr;
-use tracing::trace;
+use tracing::{debug, trace};
 use url::Url;
 
 lazy_static::lazy_static! {
@@ -16,6 +16,11 @@ lazy_static::lazy_static! {
     static ref SOURCE_REGEX: Regex = Regex::from_str(r#"rel="canonical" href="([^"]*)""#).expect("failure in setting up essential regex");
     static ref ARTIST_REGEX: Regex = Regex::from_str(r#"https://www.deviantart.com/([^/]*)/art"#).expect("failure in setting up essential regex");
     static ref SERIAL_REGEX: Regex = Regex::from_str(r#"https://www.deviantart.com/(?:.*?)-(\d+)\z"#).expect("failure in setting up essential regex");
+    static ref TITLE_REGEX: Regex = Regex::from_str(r#"\A(.*) by \S+ on DeviantArt\z"#).expect("failure in setting up essential regex");
+    static ref DEVIATION_ID_REGEX: Regex = Regex::from_str(r#"\\?"deviationId\\?":(\d+)"#).expect("failure in setting up essential regex");
+    static ref PUBLISHED_REGEX: Regex = Regex::from_str(r#"\\?"publishedTime\\?":\\?"([^"\\]+)"#).expect("failure in setting up essential regex");
+    static ref TAG_REGEX: Regex = Regex::from_str(r#"deviantart\.com\\?/tag\\?/([^"\\/?#]+)"#).expect("failure in setting up essential regex");
+    static ref MATURE_REGEX: Regex = Regex::from_str(r#"\\?"isMature\\?":(true|false)"#).expect("failure in setting up essential regex");
     static ref CDNINT_REGEX: Regex = Regex::from_str(r#"(https://images-wixmp-[0-9a-f]+.wixmp.com)(?:/intermediary)?/f/([^/]*)/([^/?]*)"#).expect("failure in setting up essential regex");
     static ref PNG_REGEX: Regex = Regex::from_str(r#"(https://[0-9a-z\-\.]+(?:/intermediary)?/f/[0-9a-f\-]+/[0-9a-z\-]+\.png/v1/fill/[0-9a-z_,]+/[0-9a-z_\-]+)(\.png)(.*)"#).expect("failure in setting up essential regex");
     static ref JPG_REGEX: Regex = Regex::from_str(r#"(https://[0-9a-z\-\.]+(?:/intermediary)?/f/[0-9a-f\-]+/[0-9a-z\-]+\.jpg/v1/fill/w_[0-9]+,h_[0-9]+,q_)([0-9]+)(,[a-z]+\/[a-z0-6_\-]+\.jpe?g.*)"#).expect("failure in setting up essential regex");
@@ -100,21 +105,118 @@ async fn extract_data(config: &Configuration, body: &str) -> Result<Option<(Scra
 
     trace!("camo_url: {}", camo);
 
+    report_progress(ScrapeProgress::MetadataFetched);
+    let preview = ScrapeImage::new(
+        Url::parse(image).context("image URL not valid URL")?,
+        camo.clone(),
+    );
+    report_progress(ScrapeProgress::ImageResolved {
+        image: Box::new(preview.clone()),
+    });
+
+    let deviation_id = SERIAL_REGEX
+        .captures(source)
+        .map(|caps| caps[1].to_string());
+    let state = deviation_id
+        .as_deref()
+        .and_then(|id| deviation_state(body, id));
+    if state.is_none() {
+        debug!("no page state for deviation {:?}", deviation_id);
+    }
+    let (title, published_at, content_rating) = extract_metadata(body, state);
+
     Ok(Some((
-        ScrapeResult::Ok(ScrapeResultData {
+        ScrapeResult::Ok(Box::new(ScrapeResultData {
             source_url: Some(Url::parse(source).context("source URL not valid URL")?),
             author_name: Some(artist.to_string()),
-            additional_tags: None,
+            additional_tags: state.map(extract_tags).filter(|x| !x.is_empty()),
             description: None,
-            images: vec![ScrapeImage {
-                url: Url::parse(image).context("image URL not valid URL")?,
-                camo_url: camo.clone(),
-            }],
-        }),
+            images: vec![preview],
+            title,
+            published_at,
+            content_rating,
+            author_url: Some(
+                Url::parse(&format!("https://www.deviantart.com/{artist}"))
+                    .context("artist URL not valid URL")?,
+            ),
+            author_display_name: None,
+            authors: Vec::new(),
+            source_urls: Vec::new(),
+            unmapped_tags: Vec::new(),
+        })),
         camo,
     )))
 }
 
+/// Returns the part of the embedded page state that describes the deviation with this id.
+///
+/// The state also holds the other deviations shown on the page, like the ones of the artist
+/// below it, so anything read from the whole page may belong to one of those.
+fn deviation_state<'a>(body: &'a str, deviation_id: &str) -> Option<&'a str> {
+    let mut ids = DEVIATION_ID_REGEX.captures_iter(body);
+    let start = ids.find(|caps| &caps[1] == deviation_id)?.get(0)?.start();
+    let end = ids
+        .next()
+        .and_then(|x| x.get(0))
+        .map_or(body.len(), |x| x.start());
+    Some(&body[start..end])
+}
+
+/// Extracts the title of the deviation from its page, publication date and mature flag
+/// from its own part of the page state.
+fn extract_metadata(
+    body: &str,
+    state: Option<&str>,
+) -> (
+    Option<String>,
+    Option<chrono::DateTime<chrono::Utc>>,
+    Option<ContentRating>,
+) {
+    let dom = visdom::Vis::load(body).ok();
+    let title = dom
+        .and_then(|dom| {
+            dom.find(r#"meta[property="og:title"]"#)
+                .first()
+                .attr("content")
+        })
+        .map(|x| {
+            use htmlentity::entity::ICodedDataTrait;
+            let x = x.to_string();
+            htmlentity::entity::decode(x.as_bytes())
+                .to_string()
+                .unwrap_or(x)
+        })
+        .map(|x| match TITLE_REGEX.captures(&x) {
+            Some(caps) => caps[1].to_string(),
+            None => x,
+        })
+        .filter(|x| !x.trim().is_empty());
+    let published_at = state
+        .and_then(|state| PUBLISHED_REGEX.captures(state))
+        .and_then(|caps| {
+            chrono::DateTime::parse_from_rfc3339(&caps[1])
+                .or_else(|_| chrono::DateTime::parse_from_str(&caps[1], "%Y-%m-%dT%H:%M:%S%z"))
+                .ok()
+        })
+        .map(|x| x.with_timezone(&chrono::Utc));
+    let content_rating = state
+        .and_then(|state| MATURE_REGEX.captures(state))
+        .map(|caps| ContentRating::from_sensitive_flag(&caps[1] == "true"));
+    (title, published_at, content_rating)
+}
+
+/// Extracts the tags of the deviation from the tag links in its part of the page state.
+fn extract_tags(state: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for caps in TAG_REGEX.captures_iter(state) {
+        let tag = caps[1].to_string();
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
 #[tracing::instrument(skip(config))]
 async fn try_intermediary_hires(
     config: &Configuration,
@@ -152,10 +254,7 @@ async fn try_intermediary_hires(
             .status()
             == 200
         {
-            images.push(ScrapeImage {
-                url: built_url,
-                camo_url: image.camo_url,
-            })
+            push_resolved(&mut images, ScrapeImage::new(built_url, image.camo_url));
         }
     }
     Ok(images)
@@ -170,20 +269,20 @@ async fn try_new_hires(mut images: Vec<ScrapeImage>) -> Result<Vec<ScrapeImage>>
                 format!("{}.png{}", &caps[1], &caps[3])
             });
             let new_url = Url::from_str(&new_url).context("could not parse png url")?;
-            images.push(ScrapeImage {
-                url: new_url,
-                camo_url: image.camo_url.clone(),
-            })
+            push_resolved(
+                &mut images,
+                ScrapeImage::new(new_url, image.camo_url.clone()),
+            );
         }
         if JPG_REGEX.is_match(&old_url) {
             let new_url = JPG_REGEX.replace(&old_url, |caps: &Captures| {
                 format!("{}100{}", &caps[1], &caps[3])
             });
             let new_url = Url::from_str(&new_url).context("could not parse jpeg url")?;
-            images.push(ScrapeImage {
-                url: new_url,
-                camo_url: image.camo_url.clone(),
-            })
+            push_resolved(
+                &mut images,
+                ScrapeImage::new(new_url, image.camo_url.clone()),
+            );
         }
     }
     Ok(images)
@@ -228,15 +327,69 @@ async fn try_old_hires(
         .find(|(name, _value)| name.as_str().to_lowercase() == "location")
     {
         let loc = loc.to_str().context("location not valid string")?;
-        images.push(ScrapeImage {
-            url: Url::parse(loc).context("new old_hires location is not valid URL")?,
-            camo_url: camo.clone(),
-        });
+        push_resolved(
+            &mut images,
+            ScrapeImage::new(
+                Url::parse(loc).context("new old_hires location is not valid URL")?,
+                camo.clone(),
+            ),
+        );
         return Ok(images);
     }
     Ok(images)
 }
 
+fn push_resolved(images: &mut Vec<ScrapeImage>, image: ScrapeImage) {
+    report_progress(ScrapeProgress::ImageResolved {
+        image: Box::new(image.clone()),
+    });
+    images.push(image);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract_metadata() {
+        let body = r#"<html><head><meta property="og:title" content="Starlight &amp; Trixie by Artist-1 on DeviantArt"/></head>
+            <body><time dateTime="2021-01-01T00:00:00.000Z">Jan 1, 2021</time>
+            <script>window.__INITIAL_STATE__ = JSON.parse("{\"deviation\":{\"1\":{\"deviationId\":1,\"publishedTime\":\"2021-01-01T00:00:00-0800\",\"isMature\":false},
+            \"675587406\":{\"deviationId\":675587406,\"publishedTime\":\"2017-04-30T13:10:14-0700\",\"isMature\":true}}}");</script></body></html>"#;
+        let state = deviation_state(body, "675587406");
+        let (title, published_at, content_rating) = extract_metadata(body, state);
+        assert_eq!(Some("Starlight & Trixie".to_string()), title);
+        assert_eq!(
+            Some("2017-04-30T20:10:14+00:00".to_string()),
+            published_at.map(|x| x.to_rfc3339())
+        );
+        assert_eq!(Some(ContentRating::Questionable), content_rating);
+        // without its own state nothing is taken from the other deviations
+        let (_, published_at, content_rating) = extract_metadata(body, None);
+        assert_eq!((None, None), (published_at, content_rating));
+        assert_eq!(None, deviation_state(body, "2"));
+        assert_eq!(
+            (None, None, None),
+            extract_metadata("<html></html>", deviation_state("<html></html>", "1"))
+        );
+    }
+
+    #[test]
+    fn test_extract_tags() {
+        let body = r#"<script>window.__INITIAL_STATE__ = JSON.parse("{\"deviation\":{\"1\":{\"deviationId\":1,\"tags\":[{\"url\":\"https:\/\/www.deviantart.com\/tag\/mlp\"},
+            {\"url\":\"https:\/\/www.deviantart.com\/tag\/starlightglimmer\"},{\"url\":\"https:\/\/www.deviantart.com\/tag\/mlp\"}]},
+            \"2\":{\"deviationId\":2,\"tags\":[{\"url\":\"https:\/\/www.deviantart.com\/tag\/trixie\"}]}}}");</script>"#;
+        assert_eq!(
+            vec!["mlp".to_string(), "starlightglimmer".to_string()],
+            extract_tags(deviation_state(body, "1").unwrap())
+        );
+        assert_eq!(
+            vec!["trixie".to_string()],
+            extract_tags(deviation_state(body, "2").unwrap())
+        );
+    }
+}
+
 // DeviantArt is currently very broken
 
 // #[cfg(test)]
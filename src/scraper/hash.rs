@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+use reqwest_middleware::ClientWithMiddleware as Client;
+use sha2::{Digest, Sha256, Sha512};
+use tracing::trace;
+
+use crate::scraper::ScrapeImage;
+
+/// Downloads an image of at most `max_bytes` and records its content hashes.
+///
+/// Hashing and decoding run on the blocking thread pool, they take a while for large images.
+#[tracing::instrument(skip(client))]
+pub async fn hash_image(client: &Client, image: &mut ScrapeImage, max_bytes: usize) -> Result<()> {
+    let data = crate::scraper::fetch_bytes(client, &image.url, max_bytes)
+        .await
+        .context("could not download image for hashing")?;
+    let size_bytes = data.len() as u64;
+    let (sha256, sha512, phash) = tokio::task::spawn_blocking(move || {
+        let phash = match perceptual_hash(&data) {
+            Ok(phash) => Some(phash),
+            Err(e) => {
+                trace!("could not compute perceptual hash: {:?}", e);
+                None
+            }
+        };
+        (
+            hex::encode(Sha256::digest(&data)),
+            hex::encode(Sha512::digest(&data)),
+            phash,
+        )
+    })
+    .await
+    .context("hashing task failed")?;
+    image.sha256 = Some(sha256);
+    image.sha512 = Some(sha512);
+    image.phash = phash;
+    image.size_bytes = image.size_bytes.or(Some(size_bytes));
+    Ok(())
+}
+
+/// Computes a 64 bit difference hash of the image, hex encoded.
+///
+/// The image is scaled to 9x8 grayscale pixels and each bit records whether a pixel is
+/// brighter than its right neighbour, so re-encoded or resized copies hash (nearly) the same.
+pub fn perceptual_hash(data: &[u8]) -> Result<String> {
+    let image = crate::scraper::decode_image(data)?
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .into_luma8();
+    let mut hash: u64 = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = image.get_pixel(x, y).0[0];
+            let right = image.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | u64::from(left > right);
+        }
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(image: image::RgbImage) -> Vec<u8> {
+        let mut data = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut data, image::ImageFormat::Png).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn test_perceptual_hash_survives_resize() -> Result<()> {
+        let gradient = |w: u32, h: u32| {
+            image::RgbImage::from_fn(w, h, |x, _| {
+                let v = 255 - (x * 255 / w) as u8;
+                image::Rgb([v, v, v])
+            })
+        };
+        let small = perceptual_hash(&encode(gradient(90, 80)))?;
+        let large = perceptual_hash(&encode(gradient(900, 800)))?;
+        assert_eq!(small, large);
+        assert_eq!("ffffffffffffffff", small);
+        assert!(perceptual_hash(b"not an image").is_err());
+        // larger than the decoder limits allow
+        let wide = encode(image::RgbImage::new(
+            crate::scraper::MAX_IMAGE_DIMENSION + 1,
+            1,
+        ));
+        let err = perceptual_hash(&wide).unwrap_err();
+        assert!(format!("{err:?}").contains("limit"), "{err:?}");
+        Ok(())
+    }
+}
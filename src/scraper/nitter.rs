@@ -1,5 +1,8 @@
-use crate::scraper::{ScrapeResult, ScrapeResultData};
-use crate::{scraper::ScrapeImage, Configuration};
+use crate::scraper::{report_progress, ScrapeProgress, ScrapeResult, ScrapeResultData};
+use crate::{
+    scraper::{video_mime_type, MediaType, ScrapeImage},
+    Configuration,
+};
 use anyhow::Context;
 use anyhow::Result;
 use lazy_static::lazy_static;
@@ -70,6 +73,7 @@ pub async fn nitter_scrape(config: &Configuration, url: &Url) -> Result<Option<S
         .text()
         .await
         .context("response from nitter was incomplete")?;
+    report_progress(ScrapeProgress::MetadataFetched);
     let dom = Vis::load_options_catch(
         &dom,
         ParseOptions {
@@ -86,8 +90,21 @@ pub async fn nitter_scrape(config: &Configuration, url: &Url) -> Result<Option<S
     let author = dom.find("div.main-tweet").find(r#"a.username"#);
     let author = author.text();
     let author = author.trim_start_matches('@');
+    let display_name = dom.find("div.main-tweet").find("a.fullname").first().text();
+    let published_at = dom
+        .find("div.main-tweet")
+        .find("span.tweet-date a")
+        .first()
+        .attr("title")
+        .and_then(|x| parse_tweet_date(&x.to_string()));
     let description = dom.find(r#"div.tweet-content"#).first();
-    let description = description.text();
+    let hashtags: Vec<String> = description
+        .find("a")
+        .map(|_, x| x.text())
+        .into_iter()
+        .filter_map(|x| x.trim().strip_prefix('#').map(|x| x.to_string()))
+        .collect();
+    let description = super::description::html_to_markdown(&description.html())?;
     let source_url = dom.find(r#"[title="Open in Twitter"]"#).first();
     let source_url = source_url.attr("href");
     let source_url = match source_url {
@@ -111,22 +128,84 @@ pub async fn nitter_scrape(config: &Configuration, url: &Url) -> Result<Option<S
             let mut url = original_url.clone();
             url.set_path(&image_url);
             let camo_url = crate::camo::camo_url(config, &url).context("could not camo url")?;
-            Ok(Some(ScrapeImage { url, camo_url }))
+            let image = ScrapeImage::new(url, camo_url);
+            report_progress(ScrapeProgress::ImageResolved {
+                image: Box::new(image.clone()),
+            });
+            Ok(Some(image))
+        });
+    let videos_results: Vec<Result<Option<ScrapeImage>>> = dom
+        .find("div.main-tweet")
+        .find("div.attachments")
+        .find("video")
+        .map(|index, ele| -> Result<Option<ScrapeImage>> {
+            let video = Vis::dom(ele);
+            // HLS playlists can't be imported, only direct MP4 or WebM sources are usable
+            let sources = video.find("source").map(|_, x| {
+                let kind = x.get_attribute("type").map(|x| x.to_string());
+                (kind, x.get_attribute("src").map(|x| x.to_string()))
+            });
+            let video_url = sources
+                .into_iter()
+                .filter_map(|(kind, src)| Some((kind, src?)))
+                .chain(video.attr("data-url").map(|x| (None, x.to_string())))
+                .find_map(|(kind, src)| {
+                    let url = original_url.join(&src).ok()?;
+                    let mime = video_mime_type(kind.as_deref(), &url)?;
+                    Some((url, mime))
+                });
+            let (url, mime) = match video_url {
+                Some(video_url) => video_url,
+                None => {
+                    debug!("no MP4 or WebM source in video attachment");
+                    return Ok(None);
+                }
+            };
+            debug!("found video url {}: '{}'", index, url);
+            let poster = video
+                .attr("poster")
+                .and_then(|poster| original_url.join(&poster.to_string()).ok());
+            let camo_url = crate::camo::camo_url(config, poster.as_ref().unwrap_or(&url))
+                .context("could not camo url")?;
+            let mut image = ScrapeImage::new(url, camo_url);
+            image.media_type = MediaType::Video;
+            image.mime_type = Some(mime.to_string());
+            image.animated = Some(video.has_class("gif"));
+            report_progress(ScrapeProgress::ImageResolved {
+                image: Box::new(image.clone()),
+            });
+            Ok(Some(image))
         });
     let mut images = Vec::new();
-    for image in images_results {
+    for image in images_results.into_iter().chain(videos_results) {
         match image? {
             Some(v) => images.push(v),
             None => continue,
         }
     }
-    Ok(Some(ScrapeResult::Ok(ScrapeResultData {
+    Ok(Some(ScrapeResult::Ok(Box::new(ScrapeResultData {
         source_url: Some(source_url),
         author_name: Some(author.to_string()),
-        additional_tags: None,
+        additional_tags: Some(hashtags).filter(|x| !x.is_empty()),
         description: Some(description),
         images,
-    })))
+        title: None,
+        published_at,
+        content_rating: None,
+        author_url: Some(Url::from_str(&format!("https://twitter.com/{author}"))?),
+        author_display_name: Some(display_name).filter(|x| !x.trim().is_empty()),
+        authors: Vec::new(),
+        source_urls: Vec::new(),
+        unmapped_tags: Vec::new(),
+    }))))
+}
+
+/// Parses the tooltip of a tweet date, like `Mar 18, 2021 · 5:10 PM UTC`.
+fn parse_tweet_date(date: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let date = date.trim().strip_suffix("UTC")?.trim();
+    chrono::NaiveDateTime::parse_from_str(date, "%b %d, %Y · %I:%M %p")
+        .ok()
+        .map(|x| x.and_utc())
 }
 
 #[cfg(test)]
@@ -153,23 +232,37 @@ mod test {
         );
         let config = Configuration::default();
         let state = State::new(config.clone())?;
-        let scrape = tokio_test::block_on(scrape(&config, &state, &tweet))?.unwrap();
-        assert_eq!(ScrapeResult::Ok(ScrapeResultData{
+        let scrape = tokio_test::block_on(scrape(&config, &state, &tweet))?
+            .unwrap()
+            .without_image_metadata();
+        let mut expected = ScrapeResultData{
             source_url: Some(url::Url::parse(r#"https://twitter.com/TheOnion/status/1372594920427491335?s=20"#)?),
             author_name: Some("TheOnion".to_string()),
             additional_tags: None,
-            description: Some("Deal Alert: The Federal Government Is Cutting You A $1,400 Stimulus Check That You Can, And Should, Spend Exclusively On 93 Copies Of ‘Stardew Valley’ bit.ly/3bX25sQ".to_string()),
+            description: Some("Deal Alert: The Federal Government Is Cutting You A $1,400 Stimulus Check That You Can, And Should, Spend Exclusively On 93 Copies Of ‘Stardew Valley’ [bit.ly/3bX25sQ](https://bit.ly/3bX25sQ)".to_string()),
             images: vec![
-                ScrapeImage {
-                    url: url::Url::from_str(
+                ScrapeImage::new(url::Url::from_str(
                         &format!("https://{}/pic/media%2FEwxvzkEXAAMFg7K.jpg%3Fname%3Dorig?s=20", host),
-                    )?,
-                    camo_url: url::Url::from_str(
+                    )?, url::Url::from_str(
                         &format!("https://{}/pic/media%2FEwxvzkEXAAMFg7K.jpg%3Fname%3Dorig?s=20", host),
-                    )?,
-                }
-            ]
-        }), scrape);
+                    )?)
+            ],
+            published_at: Some(chrono::DateTime::parse_from_rfc3339("2021-03-18T17:05:00Z")?.into()),
+            author_url: Some(url::Url::parse("https://twitter.com/TheOnion")?),
+            author_display_name: Some("The Onion".to_string()),
+            ..Default::default()
+        };
+        expected.fill_authors_and_sources();
+        assert_eq!(ScrapeResult::Ok(Box::new(expected)), scrape);
         Ok(())
     }
+
+    #[test]
+    fn test_parse_tweet_date() {
+        assert_eq!(
+            Some("2021-03-18T17:10:00+00:00".to_string()),
+            parse_tweet_date("Mar 18, 2021 · 5:10 PM UTC").map(|x| x.to_rfc3339())
+        );
+        assert_eq!(None, parse_tweet_date("yesterday"));
+    }
 }
@@ -6,7 +6,10 @@ use reqwest_middleware::ClientWithMiddleware as Client;
 
 use crate::camo::camo_url;
 use crate::scraper::philomena::derpibooru::is_derpibooru;
-use crate::scraper::{ScrapeImage, ScrapeResult, ScrapeResultData};
+use crate::scraper::{
+    report_progress, Author, ContentRating, ScrapeImage, ScrapeProgress, ScrapeResult,
+    ScrapeResultData,
+};
 use crate::Configuration;
 use anyhow::{Context, Result};
 use tracing::{debug, trace};
@@ -30,6 +33,14 @@ struct PhilomenaApiImageResponse {
     uploader: Option<String>,
     description: Option<String>,
     view_url: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    mime_type: Option<String>,
+    size: Option<u64>,
+    animated: Option<bool>,
+    created_at: Option<String>,
+    #[serde(default)]
+    source_urls: Vec<String>,
 }
 
 #[tracing::instrument(skip(config))]
@@ -46,8 +57,19 @@ pub async fn philomena_scrape(config: &Configuration, url: &Url) -> Result<Optio
     };
     let client = crate::scraper::client(config)?;
     let resp: PhilomenaApiResponse = make_philomena_api_request(&client, &api_url).await?;
+    report_progress(ScrapeProgress::MetadataFetched);
     let image = resp.image;
     let image_view = Url::from_str(&image.view_url)?;
+    let camo = camo_url(config, &image_view)?;
+    let mut scrape_image = ScrapeImage::new(image_view, camo);
+    scrape_image.width = image.width;
+    scrape_image.height = image.height;
+    scrape_image.mime_type = image.mime_type.clone();
+    scrape_image.size_bytes = image.size;
+    scrape_image.animated = image.animated;
+    report_progress(ScrapeProgress::ImageResolved {
+        image: Box::new(scrape_image.clone()),
+    });
     let description = image.description;
     let description = if description.clone().unwrap_or_default().trim().is_empty() {
         None
@@ -65,14 +87,27 @@ pub async fn philomena_scrape(config: &Configuration, url: &Url) -> Result<Optio
         .map(|x| Url::from_str(&x))
         .transpose()
         .context(format!("source url: {:?}", &image.source_url))?;
-    Ok(Some(ScrapeResult::Ok(ScrapeResultData {
-        source_url,
-        author_name: image
-            .tags
+    let mut source_urls = Vec::new();
+    for url in source_url.iter().cloned().chain(
+        image
+            .source_urls
             .iter()
-            .find(|x| x.starts_with("artist:"))
-            .cloned()
-            .map(|x| x.strip_prefix("artist:").unwrap().to_string()),
+            .filter(|x| !x.trim().is_empty())
+            .filter_map(|x| Url::from_str(x).ok()),
+    ) {
+        if !source_urls.contains(&url) {
+            source_urls.push(url);
+        }
+    }
+    let authors = image
+        .tags
+        .iter()
+        .filter_map(|x| x.strip_prefix("artist:"))
+        .map(|x| Author::new(x.to_string()))
+        .collect_vec();
+    Ok(Some(ScrapeResult::Ok(Box::new(ScrapeResultData {
+        source_url: source_url.or_else(|| source_urls.first().cloned()),
+        author_name: authors.first().map(|x| x.name.clone()),
         additional_tags: {
             let add_tags = image
                 .tags
@@ -88,11 +123,34 @@ pub async fn philomena_scrape(config: &Configuration, url: &Url) -> Result<Optio
             }
         },
         description,
-        images: vec![ScrapeImage {
-            camo_url: camo_url(config, &image_view)?,
-            url: image_view,
-        }],
-    })))
+        images: vec![scrape_image],
+        title: None,
+        published_at: image
+            .created_at
+            .as_deref()
+            .and_then(|x| chrono::DateTime::parse_from_rfc3339(x).ok())
+            .map(|x| x.with_timezone(&chrono::Utc)),
+        content_rating: rating_from_tags(&image.tags),
+        author_url: None,
+        author_display_name: None,
+        authors,
+        source_urls,
+        unmapped_tags: Vec::new(),
+    }))))
+}
+
+/// Takes the most explicit rating tag of the image.
+fn rating_from_tags(tags: &[String]) -> Option<ContentRating> {
+    let has = |names: &[&str]| tags.iter().any(|x| names.contains(&x.as_str()));
+    if has(&["explicit"]) {
+        Some(ContentRating::Explicit)
+    } else if has(&["questionable", "suggestive"]) {
+        Some(ContentRating::Questionable)
+    } else if has(&["safe"]) {
+        Some(ContentRating::Safe)
+    } else {
+        None
+    }
 }
 
 #[tracing::instrument(skip(client))]
@@ -133,11 +191,9 @@ mod test {
                     additional_tags: Some(vec![]),
                     description: None,
                     images: vec![
-                        ScrapeImage {
-                            url: Url::parse("https://derpicdn.net/img/view/2017/5/1/1426211").unwrap(),
-                            camo_url: Url::parse("https://derpicdn.net/img/view/2017/5/1/1426211").unwrap(),
-                        },
+                        ScrapeImage::new(Url::parse("https://derpicdn.net/img/view/2017/5/1/1426211").unwrap(), Url::parse("https://derpicdn.net/img/view/2017/5/1/1426211").unwrap()),
                     ],
+                    ..Default::default()
                 },
             ),
             (
@@ -148,11 +204,9 @@ mod test {
                     additional_tags: Some(vec![]),
                     description: None,
                     images: vec![
-                        ScrapeImage {
-                            url: Url::parse("https://derpicdn.net/img/view/2017/5/1/1426211").unwrap(),
-                            camo_url: Url::parse("https://derpicdn.net/img/view/2017/5/1/1426211").unwrap(),
-                        },
+                        ScrapeImage::new(Url::parse("https://derpicdn.net/img/view/2017/5/1/1426211").unwrap(), Url::parse("https://derpicdn.net/img/view/2017/5/1/1426211").unwrap()),
                     ],
+                    ..Default::default()
                 },
             ),
             (
@@ -163,11 +217,9 @@ mod test {
                     additional_tags: Some(vec![]),
                     description: None,
                     images: vec![
-                        ScrapeImage {
-                            url: Url::parse("https://derpicdn.net/img/view/2012/1/2/1").unwrap(),
-                            camo_url: Url::parse("https://derpicdn.net/img/view/2012/1/2/1").unwrap(),
-                        },
+                        ScrapeImage::new(Url::parse("https://derpicdn.net/img/view/2012/1/2/1").unwrap(), Url::parse("https://derpicdn.net/img/view/2012/1/2/1").unwrap()),
                     ],
+                    ..Default::default()
                 },
             ),
             (
@@ -178,11 +230,9 @@ mod test {
                     additional_tags: Some(vec![]),
                     description: None,
                     images: vec![
-                        ScrapeImage {
-                            url: Url::parse("https://derpicdn.net/img/view/2012/1/2/1").unwrap(),
-                            camo_url: Url::parse("https://derpicdn.net/img/view/2012/1/2/1").unwrap(),
-                        },
+                        ScrapeImage::new(Url::parse("https://derpicdn.net/img/view/2012/1/2/1").unwrap(), Url::parse("https://derpicdn.net/img/view/2012/1/2/1").unwrap()),
                     ],
+                    ..Default::default()
                 },
             ),
             (
@@ -193,11 +243,9 @@ mod test {
                     additional_tags: Some(vec![]),
                     description: Some("Dash, how'd you get in my(hit by shampoo bottle)".to_string()),
                     images: vec![
-                        ScrapeImage {
-                            url: Url::parse("https://derpicdn.net/img/view/2012/6/23/17368").unwrap(),
-                            camo_url: Url::parse("https://derpicdn.net/img/view/2012/6/23/17368").unwrap(),
-                        },
+                        ScrapeImage::new(Url::parse("https://derpicdn.net/img/view/2012/6/23/17368").unwrap(), Url::parse("https://derpicdn.net/img/view/2012/6/23/17368").unwrap()),
                     ],
+                    ..Default::default()
                 },
             )
         ];
@@ -205,12 +253,9 @@ mod test {
         let state = State::new(config.clone())?;
         for (url, expected_result) in urls {
             let scrape = tokio_test::block_on(scrape(&config, &state, url));
-            let scrape = match scrape {
-                Ok(s) => s,
-                Err(e) => return Err(e),
-            };
+            let scrape = scrape?;
             let mut scrape = match scrape {
-                Some(s) => s,
+                Some(s) => s.without_image_metadata(),
                 None => anyhow::bail!("got none response from scraper"),
             };
             match &mut scrape {
@@ -222,12 +267,38 @@ mod test {
                             .set_path(x.camo_url.path().to_string().split_once("__").unwrap().0);
                     });
                     scrape.additional_tags = Some(vec![]);
+                    // every image has an upload date and a rating tag
+                    assert!(scrape.published_at.take().is_some(), "no date on {url:?}");
+                    assert!(
+                        scrape.content_rating.take().is_some(),
+                        "no rating on {url:?}"
+                    );
                 }
                 _ => panic!(),
             }
-            let expected_result = ScrapeResult::Ok(expected_result);
+            let mut expected_result = expected_result;
+            expected_result.fill_authors_and_sources();
+            let expected_result = ScrapeResult::Ok(Box::new(expected_result));
             assert_eq!(expected_result, scrape, "Failed on URL {url:?}");
         }
         Ok(())
     }
+
+    #[test]
+    fn test_rating_from_tags() {
+        let tags = |x: &[&str]| x.iter().map(|x| x.to_string()).collect_vec();
+        assert_eq!(
+            Some(ContentRating::Safe),
+            rating_from_tags(&tags(&["safe", "artist:foo"]))
+        );
+        assert_eq!(
+            Some(ContentRating::Questionable),
+            rating_from_tags(&tags(&["suggestive", "oc"]))
+        );
+        assert_eq!(
+            Some(ContentRating::Explicit),
+            rating_from_tags(&tags(&["explicit", "grimdark"]))
+        );
+        assert_eq!(None, rating_from_tags(&tags(&["oc"])));
+    }
 }
@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use reqwest_middleware::ClientWithMiddleware as Client;
+use tracing::{debug, trace};
+
+use crate::scraper::ScrapeImage;
+
+/// Metadata sniffed from the first bytes of an image.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImageMeta {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub mime_type: Option<String>,
+    pub size_bytes: Option<u64>,
+    pub animated: Option<bool>,
+}
+
+/// Fills in missing metadata of an image by fetching at most `max_bytes` of it.
+#[tracing::instrument(skip(client))]
+pub async fn probe_image(client: &Client, image: &mut ScrapeImage, max_bytes: usize) -> Result<()> {
+    if image.width.is_some() && image.height.is_some() && image.mime_type.is_some() {
+        trace!("image metadata already known");
+        return Ok(());
+    }
+    let mut resp = client
+        .get(image.url.clone())
+        .header(
+            reqwest::header::RANGE,
+            format!("bytes=0-{}", max_bytes.saturating_sub(1)),
+        )
+        .send()
+        .await
+        .context("probe request failed")?
+        .error_for_status()
+        .context("probe request returned error code")?;
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.split(';').next().unwrap_or_default().trim().to_string());
+    let size_bytes = match resp.headers().get(reqwest::header::CONTENT_RANGE) {
+        Some(range) => range
+            .to_str()
+            .ok()
+            .and_then(|x| x.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse().ok()),
+        None => resp.content_length(),
+    };
+    let mut header = Vec::new();
+    while header.len() < max_bytes {
+        match resp
+            .chunk()
+            .await
+            .context("could not read probe response")?
+        {
+            None => break,
+            Some(chunk) => header.extend_from_slice(&chunk),
+        }
+    }
+    header.truncate(max_bytes);
+    let meta = sniff(&header);
+    debug!("sniffed image metadata: {:?}", meta);
+    image.width = image.width.or(meta.width);
+    image.height = image.height.or(meta.height);
+    image.mime_type = image.mime_type.take().or(meta.mime_type).or(content_type);
+    image.size_bytes = image.size_bytes.or(size_bytes);
+    image.animated = image.animated.or(meta.animated);
+    Ok(())
+}
+
+/// Determines type, dimensions and animation of an image from its first bytes.
+pub fn sniff(header: &[u8]) -> ImageMeta {
+    use imagesize::ImageType;
+    let image_type = match imagesize::image_type(header) {
+        Ok(t) => t,
+        Err(_) => {
+            if header.starts_with(b"\x1a\x45\xdf\xa3") {
+                return ImageMeta {
+                    mime_type: Some("video/webm".to_string()),
+                    animated: Some(true),
+                    ..Default::default()
+                };
+            }
+            return ImageMeta::default();
+        }
+    };
+    let (mime_type, animated) = match image_type {
+        ImageType::Png => ("image/png", Some(find(header, b"acTL").is_some())),
+        ImageType::Gif => ("image/gif", Some(find(header, b"NETSCAPE2.0").is_some())),
+        ImageType::Jpeg => ("image/jpeg", Some(false)),
+        ImageType::Webp => (
+            "image/webp",
+            // the VP8X chunk carries the animation flag in its first byte
+            Some(header.len() > 20 && &header[12..16] == b"VP8X" && header[20] & 0x02 != 0),
+        ),
+        _ => return ImageMeta::default(),
+    };
+    let size = imagesize::blob_size(header).ok();
+    ImageMeta {
+        width: size.as_ref().and_then(|x| u32::try_from(x.width).ok()),
+        height: size.as_ref().and_then(|x| u32::try_from(x.height).ok()),
+        mime_type: Some(mime_type.to_string()),
+        size_bytes: None,
+        animated,
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|x| x == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sniff_png() {
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&640u32.to_be_bytes());
+        png.extend_from_slice(&480u32.to_be_bytes());
+        png.extend_from_slice(b"\x08\x06\x00\x00\x00");
+        let meta = sniff(&png);
+        assert_eq!(Some(640), meta.width);
+        assert_eq!(Some(480), meta.height);
+        assert_eq!(Some("image/png".to_string()), meta.mime_type);
+        assert_eq!(Some(false), meta.animated);
+    }
+
+    #[test]
+    fn test_sniff_gif() {
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&300u16.to_le_bytes());
+        gif.extend_from_slice(&200u16.to_le_bytes());
+        gif.extend_from_slice(b"\x00\x00\x00\x21\xff\x0bNETSCAPE2.0");
+        let meta = sniff(&gif);
+        assert_eq!(Some(300), meta.width);
+        assert_eq!(Some(200), meta.height);
+        assert_eq!(Some(true), meta.animated);
+    }
+
+    #[test]
+    fn test_probe_image() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        tokio_test::block_on(async {
+            let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+            png.extend_from_slice(&640u32.to_be_bytes());
+            png.extend_from_slice(&480u32.to_be_bytes());
+            png.extend_from_slice(b"\x08\x06\x00\x00\x00");
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            let url = url::Url::parse(&format!("http://{}/a.png", listener.local_addr()?))?;
+            let server = tokio::spawn(async move {
+                let (mut socket, _) = listener.accept().await?;
+                let mut request = [0u8; 1024];
+                let _ = socket.read(&mut request).await?;
+                let head = format!(
+                    "HTTP/1.1 206 Partial Content\r\ncontent-type: image/png\r\n\
+                    content-range: bytes 0-{}/12345\r\ncontent-length: {}\r\n\r\n",
+                    png.len() - 1,
+                    png.len()
+                );
+                socket.write_all(head.as_bytes()).await?;
+                socket.write_all(&png).await?;
+                anyhow::Ok(())
+            });
+            let client = crate::scraper::client(&crate::Configuration::default())?;
+            let mut image = ScrapeImage::new(url.clone(), url);
+            probe_image(&client, &mut image, 65536).await?;
+            server.await??;
+            assert_eq!((Some(640), Some(480)), (image.width, image.height));
+            assert_eq!(Some("image/png".to_string()), image.mime_type);
+            assert_eq!(Some(12345), image.size_bytes);
+            assert_eq!(Some(false), image.animated);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_sniff_unknown() {
+        assert_eq!(ImageMeta::default(), sniff(b"<html></html>"));
+    }
+}
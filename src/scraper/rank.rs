@@ -0,0 +1,200 @@
+use std::str::FromStr;
+
+use regex::Regex;
+use url::Url;
+
+use crate::scraper::ScrapeImage;
+
+lazy_static::lazy_static! {
+    static ref WIXMP_REGEX: Regex = Regex::from_str(r#"^images-wixmp-[0-9a-f]+\.wixmp\.com$"#)
+        .expect("failure in setting up essential regex");
+    static ref WIXMP_OBJECT_REGEX: Regex = Regex::from_str(r#"^(?:/intermediary)?/f/([^/]+)/([^/.]+)"#)
+        .expect("failure in setting up essential regex");
+    static ref WIXMP_FILL_REGEX: Regex = Regex::from_str(r#"/v1/fill/w_(\d+),h_(\d+)(?:,q_(\d+))?"#)
+        .expect("failure in setting up essential regex");
+    static ref TUMBLR_MEDIA_REGEX: Regex = Regex::from_str(r#"^/([a-f\d]+)/(?:[^/]+/)?(tumblr_[A-Za-z\d]+?)(?:_r\d+)?(?:_(\d+))?\.[a-z]+$"#)
+        .expect("failure in setting up essential regex");
+}
+
+/// Quality of an image variant, compares greater for better variants.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Quality {
+    pixels: u64,
+    original: bool,
+    format: u8,
+    jpeg_quality: u8,
+    size_bytes: u64,
+}
+
+/// Collapses duplicate images, groups variants of the same artwork with the best variant
+/// first and marks that variant as preferred.
+///
+/// Groups keep the order in which their first variant was found by the scraper.
+pub fn rank_images(images: Vec<ScrapeImage>) -> Vec<ScrapeImage> {
+    let mut unique: Vec<ScrapeImage> = Vec::with_capacity(images.len());
+    for image in images {
+        match unique.iter_mut().find(|x| x.url == image.url) {
+            Some(existing) => merge_metadata(existing, image),
+            None => unique.push(image),
+        }
+    }
+    let mut groups: Vec<(String, Vec<ScrapeImage>)> = Vec::new();
+    for image in unique {
+        let key = artwork_key(&image.url);
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, group)) => group.push(image),
+            None => groups.push((key, vec![image])),
+        }
+    }
+    let mut ranked = Vec::new();
+    for (_, mut group) in groups {
+        group.sort_by_key(|x| std::cmp::Reverse(quality(x)));
+        for (i, mut image) in group.into_iter().enumerate() {
+            image.preferred = i == 0;
+            ranked.push(image);
+        }
+    }
+    ranked
+}
+
+fn merge_metadata(existing: &mut ScrapeImage, other: ScrapeImage) {
+    existing.width = existing.width.or(other.width);
+    existing.height = existing.height.or(other.height);
+    existing.mime_type = existing.mime_type.take().or(other.mime_type);
+    existing.size_bytes = existing.size_bytes.or(other.size_bytes);
+    existing.animated = existing.animated.or(other.animated);
+}
+
+/// Key shared by all variants of the same artwork.
+fn artwork_key(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    if WIXMP_REGEX.is_match(host) {
+        if let Some(caps) = WIXMP_OBJECT_REGEX.captures(url.path()) {
+            return format!("wixmp:{}/{}", &caps[1], &caps[2]);
+        }
+    }
+    if host.ends_with("media.tumblr.com") {
+        if let Some(caps) = TUMBLR_MEDIA_REGEX.captures(url.path()) {
+            return format!("tumblr:{}/{}", &caps[1], &caps[2]);
+        }
+    }
+    let mut url = url.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    url.to_string()
+}
+
+fn quality(image: &ScrapeImage) -> Quality {
+    let fill = WIXMP_FILL_REGEX.captures(image.url.path());
+    let (hint_width, hint_height, jpeg_quality) = match &fill {
+        None => (None, None, None),
+        Some(caps) => (
+            caps[1].parse::<u64>().ok(),
+            caps[2].parse::<u64>().ok(),
+            caps.get(3).and_then(|x| u8::from_str(x.as_str()).ok()),
+        ),
+    };
+    let tumblr_width = TUMBLR_MEDIA_REGEX
+        .captures(image.url.path())
+        .and_then(|caps| caps.get(3).and_then(|x| x.as_str().parse::<u64>().ok()));
+    let pixels = match (image.width, image.height) {
+        (Some(w), Some(h)) => u64::from(w) * u64::from(h),
+        _ => match (hint_width, hint_height) {
+            (Some(w), Some(h)) => w * h,
+            // an original of unknown size is at least as large as any preview of it
+            _ => tumblr_width.map(|w| w * w).unwrap_or(u64::MAX),
+        },
+    };
+    let mime_type = image.mime_type.clone().unwrap_or_else(|| {
+        let path = image.url.path().to_lowercase();
+        let ext = path.rsplit('.').next().unwrap_or_default();
+        match ext {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "webp" => "image/webp",
+            "gif" => "image/gif",
+            _ => "",
+        }
+        .to_string()
+    });
+    Quality {
+        pixels,
+        original: fill.is_none(),
+        format: match mime_type.as_str() {
+            "image/png" | "image/svg+xml" => 4,
+            "image/webp" => 3,
+            "image/jpeg" => 2,
+            "image/gif" => 1,
+            _ => 0,
+        },
+        jpeg_quality: jpeg_quality.unwrap_or(100),
+        size_bytes: image.size_bytes.unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use itertools::Itertools;
+
+    fn image(url: &str) -> ScrapeImage {
+        let url = Url::parse(url).unwrap();
+        ScrapeImage::new(url.clone(), url)
+    }
+
+    #[test]
+    fn test_rank_deviantart_variants() {
+        let base = "https://images-wixmp-ed30a86b8c4ca887773594c2.wixmp.com";
+        let preview = format!("{base}/f/86a8f3ea/d69b5bz-9498b591.jpg/v1/fill/w_1280,h_931,q_75,strp/luna-fullview.jpg?token=a");
+        let hq = format!("{base}/f/86a8f3ea/d69b5bz-9498b591.jpg/v1/fill/w_1280,h_931,q_100,strp/luna-fullview.jpg?token=a");
+        let intermediary = format!("{base}/intermediary/f/86a8f3ea/d69b5bz-9498b591.jpg");
+        let mut original = image(&intermediary);
+        original.width = Some(2000);
+        original.height = Some(1454);
+        let ranked = rank_images(vec![
+            image(&preview),
+            image(&hq),
+            image(&preview),
+            original.clone(),
+        ]);
+        let urls = ranked.iter().map(|x| x.url.to_string()).collect_vec();
+        assert_eq!(vec![intermediary, hq, preview], urls);
+        assert_eq!(
+            vec![true, false, false],
+            ranked.iter().map(|x| x.preferred).collect_vec()
+        );
+    }
+
+    #[test]
+    fn test_rank_unknown_original() {
+        let base = "https://images-wixmp-ed30a86b8c4ca887773594c2.wixmp.com";
+        let preview = format!("{base}/f/86a8f3ea/d69b5bz-9498b591.jpg/v1/fill/w_1280,h_931,q_100,strp/luna-fullview.jpg?token=a");
+        let intermediary = format!("{base}/intermediary/f/86a8f3ea/d69b5bz-9498b591.jpg");
+        let ranked = rank_images(vec![image(&preview), image(&intermediary)]);
+        let urls = ranked.iter().map(|x| x.url.to_string()).collect_vec();
+        assert_eq!(vec![intermediary, preview], urls);
+        assert!(ranked[0].preferred);
+    }
+
+    #[test]
+    fn test_rank_keeps_distinct_images() {
+        let ranked = rank_images(vec![
+            image("https://64.media.tumblr.com/abc/tumblr_xyz1_400.png"),
+            image("https://64.media.tumblr.com/def/tumblr_xyz2_1280.png"),
+            image("https://64.media.tumblr.com/abc/tumblr_xyz1_1280.png"),
+        ]);
+        let urls = ranked.iter().map(|x| x.url.to_string()).collect_vec();
+        assert_eq!(
+            vec![
+                "https://64.media.tumblr.com/abc/tumblr_xyz1_1280.png",
+                "https://64.media.tumblr.com/abc/tumblr_xyz1_400.png",
+                "https://64.media.tumblr.com/def/tumblr_xyz2_1280.png",
+            ],
+            urls
+        );
+        assert_eq!(
+            vec![true, false, true],
+            ranked.iter().map(|x| x.preferred).collect_vec()
+        );
+    }
+}
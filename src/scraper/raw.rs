@@ -1,4 +1,4 @@
-use crate::scraper::{ScrapeResult, ScrapeResultData};
+use crate::scraper::{report_progress, ScrapeProgress, ScrapeResult, ScrapeResultData};
 use crate::{scraper::ScrapeImage, Configuration};
 use anyhow::Result;
 use url::Url;
@@ -28,16 +28,25 @@ pub async fn is_raw(url: &Url, config: &Configuration) -> Result<bool> {
 
 #[tracing::instrument(skip(config))]
 pub async fn raw_scrape(config: &Configuration, url: &Url) -> Result<Option<ScrapeResult>> {
-    Ok(Some(ScrapeResult::Ok(ScrapeResultData {
+    let image = ScrapeImage::new(url.clone(), crate::camo::camo_url(config, url)?);
+    report_progress(ScrapeProgress::ImageResolved {
+        image: Box::new(image.clone()),
+    });
+    Ok(Some(ScrapeResult::Ok(Box::new(ScrapeResultData {
         source_url: Some(url.clone()),
         author_name: None,
         additional_tags: None,
         description: None,
-        images: Vec::from([ScrapeImage {
-            url: url.clone(),
-            camo_url: crate::camo::camo_url(config, url)?,
-        }]),
-    })))
+        images: Vec::from([image]),
+        title: None,
+        published_at: None,
+        content_rating: None,
+        author_url: None,
+        author_display_name: None,
+        authors: Vec::new(),
+        source_urls: Vec::new(),
+        unmapped_tags: Vec::new(),
+    }))))
 }
 
 #[cfg(test)]
@@ -56,25 +65,23 @@ mod test {
         let config = Configuration::default();
         let state = State::new(config.clone())?;
         let scrape = tokio_test::block_on(scrape(&config, &state, url));
-        let scrape = match scrape {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
+        let scrape = scrape?;
         let scrape = match scrape {
             Some(s) => s,
             None => anyhow::bail!("got none response from scraper"),
         };
-        let expected_result = ScrapeResult::Ok(ScrapeResultData {
+        let expected_result = ScrapeResult::Ok(Box::new(ScrapeResultData {
             source_url: Some(url::Url::from_str(url)?),
             author_name: None,
             additional_tags: None,
             description: None,
-            images: Vec::from([ScrapeImage {
-                url: url::Url::from_str(url)?,
-                camo_url: url::Url::from_str(url)?,
-            }]),
-        });
-        assert_eq!(expected_result, scrape);
+            images: Vec::from([ScrapeImage::new(
+                url::Url::from_str(url)?,
+                url::Url::from_str(url)?,
+            )]),
+            ..Default::default()
+        }));
+        assert_eq!(expected_result, scrape.without_image_metadata());
         Ok(())
     }
 }
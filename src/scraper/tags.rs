@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use regex::Regex;
+use serde::Deserialize;
+use tracing::trace;
+
+use super::ScrapeResultData;
+
+/// Rules file as written by the operator, see `TAG_RULES_FILE`.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TagRulesFile {
+    /// Upstream tag to booru tags.
+    #[serde(default)]
+    exact: BTreeMap<String, Vec<String>>,
+    /// Patterns tried in order on tags without an exact mapping, the tags may refer to
+    /// capture groups as `$1` or `$name`.
+    #[serde(default)]
+    regex: Vec<RegexRule>,
+    /// Booru tag to the tag it is an alias of, applied to all output tags.
+    #[serde(default)]
+    aliases: BTreeMap<String, String>,
+    /// Upstream tags which are dropped silently.
+    #[serde(default)]
+    denylist: Vec<String>,
+    /// Whether tags no rule matched are still returned in `additional_tags`.
+    #[serde(default = "default_true")]
+    keep_unmapped: bool,
+    /// Whether tags, including the ones in the rules, are lowercased before matching.
+    #[serde(default = "default_true")]
+    lowercase: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegexRule {
+    pattern: String,
+    tags: Vec<String>,
+}
+
+/// Maps upstream tags and hashtags to the tags of the booru.
+pub struct TagRules {
+    exact: BTreeMap<String, Vec<String>>,
+    regex: Vec<(Regex, Vec<String>)>,
+    aliases: BTreeMap<String, String>,
+    denylist: Vec<String>,
+    keep_unmapped: bool,
+    lowercase: bool,
+    /// Whether a rules file was loaded, without one tags are passed through untouched.
+    configured: bool,
+}
+
+impl Default for TagRules {
+    fn default() -> Self {
+        Self {
+            exact: BTreeMap::new(),
+            regex: Vec::new(),
+            aliases: BTreeMap::new(),
+            denylist: Vec::new(),
+            keep_unmapped: true,
+            lowercase: false,
+            configured: false,
+        }
+    }
+}
+
+impl TagRules {
+    /// Loads the rules from a JSON file, no file maps nothing and keeps all tags.
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        match path {
+            None => Ok(Self::default()),
+            Some(path) => {
+                let data = std::fs::read_to_string(path)
+                    .with_context(|| format!("could not read tag rules {path:?}"))?;
+                Self::parse(&data).with_context(|| format!("invalid tag rules {path:?}"))
+            }
+        }
+    }
+
+    fn parse(data: &str) -> Result<Self> {
+        Self::from_file(serde_json::from_str(data)?)
+    }
+
+    fn from_file(file: TagRulesFile) -> Result<Self> {
+        let lowercase = file.lowercase;
+        let normalize = |tag: &str| normalize(tag, lowercase);
+        Ok(Self {
+            exact: file
+                .exact
+                .into_iter()
+                .map(|(k, v)| (normalize(&k), v))
+                .collect(),
+            regex: file
+                .regex
+                .into_iter()
+                .map(|rule| {
+                    Regex::new(&rule.pattern)
+                        .with_context(|| format!("invalid tag pattern {:?}", rule.pattern))
+                        .map(|re| (re, rule.tags))
+                })
+                .collect::<Result<_>>()?,
+            aliases: file
+                .aliases
+                .into_iter()
+                .map(|(k, v)| (normalize(&k), normalize(&v)))
+                .collect(),
+            denylist: file.denylist.iter().map(|x| normalize(x)).collect(),
+            keep_unmapped: file.keep_unmapped,
+            lowercase,
+            configured: true,
+        })
+    }
+
+    fn normalize(&self, tag: &str) -> String {
+        normalize(tag, self.lowercase)
+    }
+
+    fn alias(&self, tag: String) -> String {
+        self.aliases.get(&tag).cloned().unwrap_or(tag)
+    }
+
+    /// Maps a single normalized tag, `None` if no rule matched.
+    fn map_tag(&self, tag: &str) -> Option<Vec<String>> {
+        if let Some(tags) = self.exact.get(tag) {
+            return Some(tags.iter().map(|x| self.normalize(x)).collect());
+        }
+        self.regex.iter().find_map(|(re, tags)| {
+            let captures = re.captures(tag)?;
+            Some(
+                tags.iter()
+                    .map(|template| {
+                        let mut out = String::new();
+                        captures.expand(template, &mut out);
+                        self.normalize(&out)
+                    })
+                    .collect(),
+            )
+        })
+    }
+
+    /// Replaces the upstream `additional_tags` of a result with booru tags, adding an
+    /// `artist:` tag for every author and listing tags without a rule in `unmapped_tags`.
+    ///
+    /// Without a rules file the tags are left as the scraper returned them.
+    pub fn apply(&self, data: &mut ScrapeResultData) {
+        if !self.configured {
+            return;
+        }
+        let mut tags = Vec::new();
+        let mut artists: Vec<String> = data
+            .authors
+            .iter()
+            .map(|x| self.normalize(&x.name))
+            .collect();
+        let mut unmapped = Vec::new();
+        for tag in data.additional_tags.take().unwrap_or_default() {
+            let tag = self.normalize(&tag);
+            if tag.is_empty() || self.denylist.contains(&tag) {
+                continue;
+            }
+            if let Some(artist) = tag.strip_prefix("artist:") {
+                artists.push(artist.trim().to_string());
+                continue;
+            }
+            match self.map_tag(&tag) {
+                Some(mapped) => {
+                    trace!("mapped tag {:?} to {:?}", tag, mapped);
+                    tags.extend(mapped);
+                }
+                None => {
+                    if self.keep_unmapped {
+                        tags.push(tag.clone());
+                    }
+                    unmapped.push(tag);
+                }
+            }
+        }
+        tags.extend(
+            artists
+                .into_iter()
+                .filter(|x| !x.is_empty())
+                .map(|x| format!("artist:{x}")),
+        );
+        let tags = tags
+            .into_iter()
+            .filter(|x| !x.is_empty())
+            .map(|x| self.alias(x))
+            .filter(|x| !self.denylist.contains(x))
+            .unique()
+            .collect_vec();
+        data.additional_tags = if tags.is_empty() { None } else { Some(tags) };
+        data.unmapped_tags = unmapped.into_iter().unique().collect();
+    }
+}
+
+/// Strips a leading `#` from a tag and collapses whitespace, lowercasing it if asked to.
+fn normalize(tag: &str, lowercase: bool) -> String {
+    let tag = tag
+        .trim()
+        .trim_start_matches('#')
+        .split_whitespace()
+        .join(" ");
+    if lowercase {
+        tag.to_lowercase()
+    } else {
+        tag
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scraper::Author;
+
+    #[test]
+    fn test_apply_rules() -> Result<()> {
+        let rules = TagRules::parse(
+            r#"{
+                "exact": {"MLP": ["my little pony"], "fim": ["my little pony", "g4"]},
+                "regex": [{"pattern": "^(?P<name>.+)fanart$", "tags": ["fan art", "$name"]}],
+                "aliases": {"twi": "twilight sparkle", "artist:old name": "artist:new name"},
+                "denylist": ["art", "follow me"],
+                "keep_unmapped": false
+            }"#,
+        )?;
+        let mut data = ScrapeResultData {
+            additional_tags: Some(
+                [
+                    "#mlp",
+                    "FiM",
+                    "twifanart",
+                    "Art",
+                    "something",
+                    "artist:Old  Name",
+                ]
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+            ),
+            authors: vec![Author::new("Someone".to_string())],
+            ..Default::default()
+        };
+        rules.apply(&mut data);
+        assert_eq!(
+            Some(vec![
+                "my little pony".to_string(),
+                "g4".to_string(),
+                "fan art".to_string(),
+                "twilight sparkle".to_string(),
+                "artist:someone".to_string(),
+                "artist:new name".to_string(),
+            ]),
+            data.additional_tags
+        );
+        assert_eq!(vec!["something".to_string()], data.unmapped_tags);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_rules() {
+        let mut data = ScrapeResultData {
+            additional_tags: Some(vec!["Safe".to_string(), "#Fan  Art".to_string()]),
+            authors: vec![Author::new("Artist".to_string())],
+            ..Default::default()
+        };
+        TagRules::default().apply(&mut data);
+        assert_eq!(
+            Some(vec!["Safe".to_string(), "#Fan  Art".to_string()]),
+            data.additional_tags
+        );
+        assert!(data.unmapped_tags.is_empty());
+        let mut data = ScrapeResultData {
+            additional_tags: Some(vec!["Safe".to_string()]),
+            ..Default::default()
+        };
+        TagRules::parse(r#"{"lowercase": false}"#)
+            .expect("valid rules")
+            .apply(&mut data);
+        assert_eq!(Some(vec!["Safe".to_string()]), data.additional_tags);
+        assert_eq!(vec!["Safe".to_string()], data.unmapped_tags);
+        assert!(TagRules::parse(r#"{"regex": [{"pattern": "(", "tags": []}]}"#).is_err());
+    }
+}
@@ -1,89 +1,130 @@
-use reqwest_middleware::ClientWithMiddleware as Client;
+use reqwest_middleware::{ClientWithMiddleware as Client, RequestBuilder};
 use std::str::FromStr;
 use tracing::{debug, trace};
 
-use crate::TumblrDnsCache;
 use crate::{
     camo::camo_url,
-    scraper::{ScrapeImage, ScrapeResult, ScrapeResultData},
+    scraper::{
+        report_progress, video_mime_type, Author, ContentRating, MediaType, ScrapeImage,
+        ScrapeProgress, ScrapeResult, ScrapeResultData,
+    },
     Configuration,
 };
+use crate::{State, TumblrUpsizeCache};
 use anyhow::{Context, Result};
-use ipnet::IpNet;
 use regex::{Captures, Regex};
 use serde_json::Value;
 use url::Url;
 
+mod auth;
+mod npf;
+mod page;
+mod resolver;
+
+pub use auth::TumblrAuth;
+pub use resolver::TumblrResolver;
+
 lazy_static::lazy_static! {
     static ref URL_REGEX: Regex = Regex::from_str(r#"https?://(.*)/(image|post)/(\d+).*"#)
         .expect("failure in setting up essential regex");
-    static ref MEDIA_REGEX: Regex = Regex::from_str(r#"(https?://(?:\d+\.)?media\.tumblr\.com/[a-f\d]+/[a-f\d]+-[a-f\d]+/s\d+x\d+/[a-f\d]+\.(?:png|jpe?g|gif))"#)
-        .expect("failure in setting up essential regex");
     static ref SIZE_REGEX: Regex = Regex::from_str(r#"_(\d+)(\..+)\z"#)
         .expect("failure in setting up essential regex");
-    static ref TUMBLR_RANGES: Vec<IpNet> = IpNet::aggregate(&Vec::from([
-        IpNet::from_str("66.6.32.0/24").unwrap(),
-        IpNet::from_str("66.6.33.0/24").unwrap(),
-        IpNet::from_str("66.6.44.0/24").unwrap(),
-        IpNet::from_str("74.114.152.0/24").unwrap(),
-        IpNet::from_str("74.114.153.0/24").unwrap(),
-        IpNet::from_str("74.114.154.0/24").unwrap(),
-        IpNet::from_str("74.114.155.0/24").unwrap(),
-    ]));
+    static ref DIMENSION_REGEX: Regex = Regex::from_str(r#"/s(\d+)x(\d+)(_[a-z0-9]+)?/"#)
+        .expect("failure in setting up essential regex");
     static ref TUMBLR_SIZES: Vec<u64> = vec![1280, 540, 500, 400, 250, 100, 75];
+    static ref TUMBLR_DIMENSIONS: Vec<(u64, u64)> = vec![
+        (2048, 3072), (1280, 1920), (640, 960), (540, 810), (500, 750), (400, 600),
+        (250, 400), (100, 200),
+    ];
 }
 
-#[tracing::instrument]
-pub async fn is_tumblr(dns_cache: TumblrDnsCache, url: &Url) -> Result<bool> {
-    if URL_REGEX.is_match_at(url.as_str(), 0) {
-        trace!("tumblr matched on regex URL");
-        return Ok(true);
+/// Matches post URLs of blogs on tumblr.com and, after resolving the host, on custom domains.
+#[tracing::instrument(skip(config, state))]
+pub async fn is_tumblr(config: &Configuration, state: &State, url: &Url) -> Result<bool> {
+    if !URL_REGEX.is_match_at(url.as_str(), 0) {
+        return Ok(false);
     }
-    trace!("tumblr didn't match on regex, trying host resolver");
-    Ok(match url.host() {
-        Some(host) => tumblr_domain(dns_cache, host).await?,
-        None => false,
-    })
+    let host = match url.host() {
+        None => return Ok(false),
+        Some(host) => host,
+    };
+    if let url::Host::Domain(domain) = host {
+        if domain == "tumblr.com" || domain.ends_with(".tumblr.com") {
+            trace!("tumblr matched on regex URL");
+            return Ok(true);
+        }
+    }
+    trace!("post url on custom domain, trying host resolver");
+    Ok(state
+        .tumblr_resolver
+        .is_tumblr_host(config, &state.tumblr_auth, host)
+        .await)
 }
 
-#[tracing::instrument]
-async fn tumblr_domain(dns_cache: TumblrDnsCache, host: url::Host<&str>) -> Result<bool> {
-    let host = host.to_string();
-    let r = dns_cache
-        .try_get_with::<_, anyhow::Error>(host.clone(), async move {
-            let host = host.clone();
-            let hosts =
-                tokio::task::spawn_blocking(move || dns_lookup::lookup_host(&host)).await??;
-            //trace!("got hosts for URL: {:?}", hosts);
-            for host in hosts {
-                if TUMBLR_RANGES.iter().any(|net| net.contains(&host)) {
-                    return Ok(true);
+/// Asks the API whether a blog is served on the given host, `false` without an API key.
+///
+/// `None` if the API refused the lookup for missing credentials or a rate limit, or it
+/// failed, so the answer isn't cached.
+#[tracing::instrument(skip(config, auth))]
+async fn blog_exists(config: &Configuration, auth: &TumblrAuth, host: &str) -> Option<bool> {
+    let res = async {
+        let api_url = Url::parse(&format!("https://api.tumblr.com/v2/blog/{host}/info"))
+            .context("invalid tumblr api url")?;
+        let client = crate::scraper::client(config)?;
+        let request = match auth.request(config, &client, api_url).await {
+            None => return Ok(Some(false)),
+            Some(request) => request,
+        };
+        let resp = request.send().await.context("request to tumblr failed")?;
+        match resp.status().as_u16() {
+            200 => Ok(Some(true)),
+            404 => Ok(Some(false)),
+            401 | 429 => {
+                debug!("tumblr api refused blog lookup: {}", resp.status());
+                if resp.status().as_u16() == 401 {
+                    auth.invalidate().await;
                 }
+                Ok(None)
             }
-            trace!("host not in URL list");
-            Ok(false)
-        })
-        .await;
-    r.map_err(|e| anyhow::format_err!("{:?}", e))
+            status => anyhow::bail!("tumblr api returned {} for blog lookup", status),
+        }
+    };
+    res.await.unwrap_or_else(|e: anyhow::Error| {
+        debug!("could not look up blog: {:?}", e);
+        None
+    })
 }
 
-#[tracing::instrument(skip(client))]
-async fn make_tumblr_api_request(client: &Client, api_url: &str) -> Result<Value> {
+/// Runs an API request, `None` if the API refused it for missing credentials or a rate limit.
+#[tracing::instrument(skip(auth, request))]
+async fn make_tumblr_api_request(
+    auth: &TumblrAuth,
+    request: RequestBuilder,
+) -> Result<Option<Value>> {
     debug!("running api request, not in cache");
-    client
-        .get(api_url)
-        .send()
-        .await
-        .context("request to tumblr failed")?
-        .error_for_status()
+    let resp = request.send().await.context("request to tumblr failed")?;
+    if matches!(resp.status().as_u16(), 401 | 429) {
+        debug!("tumblr api refused request: {}", resp.status());
+        if resp.status().as_u16() == 401 {
+            auth.invalidate().await;
+        }
+        return Ok(None);
+    }
+    resp.error_for_status()
         .context("request to tumblr returned error code")?
         .json()
         .await
         .context("could not parse tumblr response as json")
+        .map(Some)
 }
 
-#[tracing::instrument(skip(config))]
-pub async fn tumblr_scrape(config: &Configuration, url: &Url) -> Result<Option<ScrapeResult>> {
+#[tracing::instrument(skip(config, upsize_cache, auth))]
+pub async fn tumblr_scrape(
+    config: &Configuration,
+    upsize_cache: TumblrUpsizeCache,
+    auth: TumblrAuth,
+    url: &Url,
+) -> Result<Option<ScrapeResult>> {
     trace!("analyzing tumblr url {}", url);
     let post_id = URL_REGEX.captures(url.as_str());
     let post_id = match post_id {
@@ -93,46 +134,87 @@ pub async fn tumblr_scrape(config: &Configuration, url: &Url) -> Result<Option<S
     trace!("captured: {:?}", post_id);
     let post_id = &post_id[3];
     trace!("tumblr blog id: {}", post_id);
-    let api_key = config.tumblr_api_key.as_ref();
-    let api_key = match api_key {
-        None => "",
-        Some(s) => s.as_str(),
-    };
     let host = url.host_str();
     let host = match host {
         None => return Ok(None),
         Some(p) => p,
     };
-    let api_url = format!(
-        r#"https://api.tumblr.com/v2/blog/{host}/posts/photo?id={post_id}&api_key={api_key}"#,
-        host = host,
-        post_id = post_id,
-        api_key = api_key
-    );
+    let api_url = Url::parse_with_params(
+        &format!("https://api.tumblr.com/v2/blog/{host}/posts"),
+        [("id", post_id), ("npf", "true"), ("reblog_info", "true")],
+    )
+    .context("invalid tumblr api url")?;
 
     let client = crate::scraper::client(config)?;
-    let resp: Value = make_tumblr_api_request(&client, &api_url).await?;
+    let request = match auth.request(config, &client, api_url).await {
+        None => {
+            debug!("no tumblr api key, scraping post page");
+            return page_scrape(config, &upsize_cache, &client, url, post_id).await;
+        }
+        Some(request) => request,
+    };
+    let resp: Value = match make_tumblr_api_request(&auth, request).await? {
+        None => return page_scrape(config, &upsize_cache, &client, url, post_id).await,
+        Some(resp) => resp,
+    };
 
     if resp["meta"]["status"] != 200 {
         anyhow::bail!("tumblr returned non-200 error");
     }
+    report_progress(ScrapeProgress::MetadataFetched);
 
+    let blog = &resp["response"]["blog"];
     let resp = &resp["response"]["posts"][0];
 
+    if npf::is_npf(resp) {
+        debug!("npf post, sending to block scraper");
+        return add_meta(
+            blog.clone(),
+            resp.clone(),
+            npf::process_post_npf(resp, config)?,
+        )
+        .await;
+    }
+    debug!("legacy post, falling back to post type");
     match resp["type"].as_str() {
         Some("photo") => {
             debug!("photo post, sending to photo scraper");
             add_meta(
+                blog.clone(),
                 resp.clone(),
-                process_post(PostType::Photo, resp.clone(), config, &client).await?,
+                process_post(
+                    PostType::Photo,
+                    resp.clone(),
+                    config,
+                    &upsize_cache,
+                    &client,
+                )
+                .await?,
             )
             .await
         }
         Some("text") => {
             debug!("text post, sending to post scraper");
             add_meta(
+                blog.clone(),
+                resp.clone(),
+                process_post(PostType::Text, resp.clone(), config, &upsize_cache, &client).await?,
+            )
+            .await
+        }
+        Some("video") => {
+            debug!("video post, sending to video scraper");
+            add_meta(
+                blog.clone(),
                 resp.clone(),
-                process_post(PostType::Text, resp.clone(), config, &client).await?,
+                process_post(
+                    PostType::Video,
+                    resp.clone(),
+                    config,
+                    &upsize_cache,
+                    &client,
+                )
+                .await?,
             )
             .await
         }
@@ -143,64 +225,189 @@ pub async fn tumblr_scrape(config: &Configuration, url: &Url) -> Result<Option<S
     }
 }
 
+/// Scrapes the public post page, for when the API can't be used.
+///
+/// The page only links the sizes shown to visitors, so images are upsized like those of
+/// legacy posts.
+#[tracing::instrument(skip(config, cache, client))]
+async fn page_scrape(
+    config: &Configuration,
+    cache: &TumblrUpsizeCache,
+    client: &Client,
+    url: &Url,
+    post_id: &str,
+) -> Result<Option<ScrapeResult>> {
+    let body = page::fetch_post_page(config, url).await?;
+    let (blog, post) = match page::post_from_page(&body, url, post_id) {
+        None => {
+            debug!("found no post in tumblr page");
+            return Ok(None);
+        }
+        Some(found) => found,
+    };
+    report_progress(ScrapeProgress::MetadataFetched);
+    let mut images = npf::process_post_npf(&post, config)?;
+    if let Some(images) = images.as_mut() {
+        upsize_images(cache, client, images).await;
+    }
+    add_meta(blog, post, images).await
+}
+
+/// Replaces the URLs of images with their largest size, dropping the dimensions of those
+/// that changed.
+async fn upsize_images(cache: &TumblrUpsizeCache, client: &Client, images: &mut [ScrapeImage]) {
+    let upsized = futures::future::join_all(
+        images
+            .iter()
+            .filter(|x| x.media_type == MediaType::Image)
+            .map(|x| upsize(cache, &x.url, client)),
+    )
+    .await;
+    let images = images
+        .iter_mut()
+        .filter(|x| x.media_type == MediaType::Image);
+    for (image, full) in images.zip(upsized) {
+        if full != image.url {
+            trace!("upsized {} to {}", image.url, full);
+            image.url = full;
+            image.width = None;
+            image.height = None;
+        }
+    }
+}
+
 #[derive(Debug)]
 enum PostType {
     Photo,
     Text,
+    Video,
 }
 
-#[tracing::instrument(skip(config, client))]
+#[tracing::instrument(skip(config, cache, client))]
 async fn process_post(
     post_type: PostType,
     post: Value,
     config: &Configuration,
+    cache: &TumblrUpsizeCache,
     client: &Client,
 ) -> Result<Option<Vec<ScrapeImage>>> {
     match post_type {
-        PostType::Photo => process_post_photo(post, config, client).await,
-        PostType::Text => process_post_text(post, config).await,
+        PostType::Photo => process_post_photo(post, config, cache, client).await,
+        PostType::Text => process_post_text(post, config, cache, client).await,
+        PostType::Video => process_post_video(post, config).await,
     }
 }
 
 #[tracing::instrument(skip(config))]
+async fn process_post_video(
+    post: Value,
+    config: &Configuration,
+) -> Result<Option<Vec<ScrapeImage>>> {
+    // embedded videos of other sites don't have a video_url
+    let video_url = match post["video_url"].as_str() {
+        None => {
+            debug!("video is not hosted on tumblr, bailing");
+            return Ok(None);
+        }
+        Some(url) => Url::from_str(url)?,
+    };
+    let poster = post["thumbnail_url"]
+        .as_str()
+        .and_then(|x| Url::from_str(x).ok())
+        .unwrap_or_else(|| video_url.clone());
+    let camo = camo_url(config, &poster)?;
+    let mime = video_mime_type(None, &video_url);
+    let mut image = ScrapeImage::new(video_url, camo);
+    image.media_type = MediaType::Video;
+    image.mime_type = mime.map(|x| x.to_string());
+    image.width = post["thumbnail_width"]
+        .as_u64()
+        .and_then(|x| u32::try_from(x).ok());
+    image.height = post["thumbnail_height"]
+        .as_u64()
+        .and_then(|x| u32::try_from(x).ok());
+    report_progress(ScrapeProgress::ImageResolved {
+        image: Box::new(image.clone()),
+    });
+    Ok(Some(vec![image]))
+}
+
+#[tracing::instrument(skip(config, cache, client))]
 async fn process_post_text(
     post: Value,
     config: &Configuration,
+    cache: &TumblrUpsizeCache,
+    client: &Client,
 ) -> Result<Option<Vec<ScrapeImage>>> {
     let body = post
         .get("body")
         .and_then(|x| x.as_str())
         .unwrap_or_default();
-    println!("{:?}", body);
-    let media_regex = MEDIA_REGEX.clone();
-    let images = media_regex.captures(body);
-    let images = match images {
-        None => return Ok(None),
-        Some(v) => v,
+    let urls = body_images(body);
+    debug!("found {} potential images", urls.len());
+    if urls.is_empty() {
+        return Ok(None);
+    }
+    let upsized =
+        futures::future::join_all(urls.iter().map(|url| upsize(cache, url, client))).await;
+    let mut images = Vec::new();
+    for (url, full) in urls.iter().zip(upsized) {
+        let image = ScrapeImage::new(full, camo_url(config, url)?);
+        report_progress(ScrapeProgress::ImageResolved {
+            image: Box::new(image.clone()),
+        });
+        images.push(image);
+    }
+    Ok(Some(images))
+}
+
+/// Finds the inline images of a text post body, taking the largest entry of the `srcset`
+/// where there is one.
+fn body_images(body: &str) -> Vec<Url> {
+    let dom = match visdom::Vis::load(body) {
+        Ok(dom) => dom,
+        Err(e) => {
+            debug!("could not parse post body: {}", e);
+            return Vec::new();
+        }
     };
-    let mut images: Vec<&str> = images
-        .iter()
-        .map(|x| x.map(|x| x.as_str()).unwrap_or_default())
-        .collect();
-    images.sort_unstable();
-    images.dedup();
-    println!("Found {} potential images", images.len());
-    let mut meta_images = Vec::new();
-    for i in images {
-        let i = Url::from_str(i)?;
-        println!("cap: {:?}", i);
-        meta_images.push(ScrapeImage {
-            camo_url: camo_url(config, &i)?,
-            url: i,
+    let mut urls: Vec<Url> = Vec::new();
+    let found = dom.find("img").map(|_, img| {
+        let srcset = img.get_attribute("srcset").map(|x| x.to_string());
+        let largest = srcset.and_then(|srcset| {
+            srcset
+                .split(',')
+                .filter_map(|entry| {
+                    let mut parts = entry.split_whitespace();
+                    let url = parts.next()?;
+                    let width = parts.next()?.strip_suffix('w')?.parse::<u32>().ok()?;
+                    Some((width, url.to_string()))
+                })
+                .max_by_key(|(width, _)| *width)
+                .map(|(_, url)| url)
         });
+        largest.or_else(|| img.get_attribute("src").map(|x| x.to_string()))
+    });
+    for url in found.into_iter().flatten() {
+        let url = match Url::from_str(&url) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+        let on_tumblr = url
+            .host_str()
+            .is_some_and(|x| x.ends_with(".media.tumblr.com") || x == "media.tumblr.com");
+        if on_tumblr && !urls.contains(&url) {
+            urls.push(url);
+        }
     }
-    Ok(Some(meta_images))
+    urls
 }
 
-#[tracing::instrument(skip(config, client))]
+#[tracing::instrument(skip(config, cache, client))]
 async fn process_post_photo(
     post: Value,
     config: &Configuration,
+    cache: &TumblrUpsizeCache,
     client: &Client,
 ) -> Result<Option<Vec<ScrapeImage>>> {
     let photos = post["photos"].as_array();
@@ -210,117 +417,358 @@ async fn process_post_photo(
             Ok(None)
         }
         Some(photos) => {
+            let results = futures::future::join_all(
+                photos
+                    .iter()
+                    .map(|photo| process_photo(photo, config, cache, client)),
+            )
+            .await;
             let mut images = Vec::new();
-            for photo in photos.iter() {
-                debug!("upsizing photo {}", photo);
-                let image = upsize(photo["original_size"]["url"].clone(), config, client).await?;
-                let image = match image {
-                    None => continue,
-                    Some(i) => i,
-                };
-                let alt_sizes = photo["alt_sizes"].as_array();
-                let preview = match alt_sizes {
-                    None => None,
-                    Some(alt_sizes) => {
-                        let mut valid_alt_sizes = Vec::new();
-                        for alt_size in alt_sizes {
-                            if alt_size["width"] == serde_json::json!(400i64) {
-                                let url = alt_size["url"].as_str();
-                                match url {
-                                    None => (),
-                                    Some(url) => {
-                                        valid_alt_sizes.push(Url::from_str(url)?);
-                                    }
-                                }
-                            }
-                        }
-                        if valid_alt_sizes.is_empty() {
-                            valid_alt_sizes.push(image.clone());
+            for image in results {
+                if let Some(image) = image? {
+                    report_progress(ScrapeProgress::ImageResolved {
+                        image: Box::new(image.clone()),
+                    });
+                    images.push(image);
+                }
+            }
+            Ok(Some(images))
+        }
+    }
+}
+
+#[tracing::instrument(skip(photo, config, cache, client))]
+async fn process_photo(
+    photo: &Value,
+    config: &Configuration,
+    cache: &TumblrUpsizeCache,
+    client: &Client,
+) -> Result<Option<ScrapeImage>> {
+    debug!("upsizing photo {}", photo);
+    let original = photo["original_size"]["url"]
+        .as_str()
+        .and_then(|x| Url::from_str(x).ok());
+    let original = match original {
+        None => {
+            trace!("no upsized image, returning");
+            return Ok(None);
+        }
+        Some(original) => original,
+    };
+    let image = upsize(cache, &original, client).await;
+    let alt_sizes = photo["alt_sizes"].as_array();
+    let preview = match alt_sizes {
+        None => None,
+        Some(alt_sizes) => {
+            let mut valid_alt_sizes = Vec::new();
+            for alt_size in alt_sizes {
+                if alt_size["width"] == serde_json::json!(400i64) {
+                    let url = alt_size["url"].as_str();
+                    match url {
+                        None => (),
+                        Some(url) => {
+                            valid_alt_sizes.push(Url::from_str(url)?);
                         }
-                        valid_alt_sizes.pop()
                     }
-                };
-                match preview {
-                    None => images.push((image.clone(), image)),
-                    Some(preview) => images.push((image, preview)),
                 }
             }
-            Ok(Some(
-                images
-                    .iter()
-                    .flat_map(|(image, preview)| -> Result<ScrapeImage> {
-                        Ok(ScrapeImage {
-                            url: image.clone(),
-                            camo_url: camo_url(config, preview)?,
-                        })
-                    })
-                    .collect(),
-            ))
+            if valid_alt_sizes.is_empty() {
+                valid_alt_sizes.push(image.clone());
+            }
+            valid_alt_sizes.pop()
+        }
+    };
+    let preview = preview.unwrap_or_else(|| image.clone());
+    match camo_url(config, &preview) {
+        Err(e) => {
+            debug!("could not camo preview {}: {}", preview, e);
+            Ok(None)
+        }
+        Ok(camo_url) => {
+            let (width, height) = photo_dimensions(photo, &image).unzip();
+            let mut image = ScrapeImage::new(image, camo_url);
+            image.width = width;
+            image.height = height;
+            Ok(Some(image))
         }
     }
 }
 
+/// Looks up the dimensions of the given URL among the sizes of a photo.
+fn photo_dimensions(photo: &Value, url: &Url) -> Option<(u32, u32)> {
+    let sizes = photo["alt_sizes"].as_array();
+    std::iter::once(&photo["original_size"])
+        .chain(sizes.into_iter().flatten())
+        .find(|size| size["url"].as_str() == Some(url.as_str()))
+        .and_then(|size| {
+            Some((
+                u32::try_from(size["width"].as_u64()?).ok()?,
+                u32::try_from(size["height"].as_u64()?).ok()?,
+            ))
+        })
+}
+
 #[tracing::instrument]
-async fn add_meta(post: Value, images: Option<Vec<ScrapeImage>>) -> Result<Option<ScrapeResult>> {
+async fn add_meta(
+    blog: Value,
+    post: Value,
+    images: Option<Vec<ScrapeImage>>,
+) -> Result<Option<ScrapeResult>> {
     match images {
         None => Ok(None),
         Some(images) => {
-            let source_url = post["post_url"].as_str().map(|x| x.to_string());
-            let source_url = source_url.map(|x| Url::from_str(&x)).transpose()?;
-            let author_name = post["blog_name"].as_str().map(|x| x.to_string());
-            let description = post["summary"].as_str().map(|x| x.to_string());
+            let post_url = post["post_url"].as_str().map(|x| x.to_string());
+            let post_url = post_url.map(|x| Url::from_str(&x)).transpose()?;
+            let blog_name = post["blog_name"].as_str().map(|x| x.to_string());
+            let description = match post["caption"].as_str().or(post["body"].as_str()) {
+                _ if npf::is_npf(&post) => npf::npf_description(&post),
+                Some(html) => Some(super::description::html_to_markdown(html)?),
+                None => post["summary"]
+                    .as_str()
+                    .map(super::description::text_to_markdown),
+            };
+            let own = match blog_name {
+                None => None,
+                Some(name) => {
+                    let mut author = Author::new(name.clone());
+                    author.url = match blog["url"].as_str() {
+                        Some(url) => Some(Url::from_str(url)?),
+                        None => Some(Url::from_str(&format!("https://{name}.tumblr.com/"))?),
+                    };
+                    author.display_name = blog["title"]
+                        .as_str()
+                        .filter(|x| !x.trim().is_empty())
+                        .map(|x| x.to_string());
+                    Some(author)
+                }
+            };
+            let (authors, source_urls) = reblog_trail(&post, own, post_url);
+            let author = authors.first();
+            let source_url = source_urls.first().cloned();
+            let author_name = author.map(|x| x.name.clone());
+            let author_url = author.and_then(|x| x.url.clone());
+            let author_display_name = author.and_then(|x| x.display_name.clone());
 
-            Ok(Some(ScrapeResult::Ok(ScrapeResultData {
+            Ok(Some(ScrapeResult::Ok(Box::new(ScrapeResultData {
                 source_url,
                 author_name,
-                additional_tags: None,
+                additional_tags: post["tags"]
+                    .as_array()
+                    .map(|tags| {
+                        tags.iter()
+                            .filter_map(|x| x.as_str())
+                            .map(|x| x.to_string())
+                            .collect::<Vec<_>>()
+                    })
+                    .filter(|x| !x.is_empty()),
                 description,
                 images,
-            })))
+                title: post["title"]
+                    .as_str()
+                    .filter(|x| !x.trim().is_empty())
+                    .map(|x| x.to_string()),
+                published_at: post["timestamp"]
+                    .as_i64()
+                    .and_then(|x| chrono::DateTime::from_timestamp(x, 0)),
+                content_rating: post["is_nsfw"]
+                    .as_bool()
+                    .or_else(|| blog["is_nsfw"].as_bool())
+                    .map(ContentRating::from_sensitive_flag),
+                author_url,
+                author_display_name,
+                authors,
+                source_urls,
+                unmapped_tags: Vec::new(),
+            }))))
         }
     }
 }
 
-#[tracing::instrument(skip(_config, client))]
-async fn upsize(image_url: Value, _config: &Configuration, client: &Client) -> Result<Option<Url>> {
-    let image_url = image_url.as_str();
-    let image_url = match image_url {
-        None => {
-            trace!("no upsized image, returning");
-            return Ok(None);
+/// Returns the blog and post a reblog originates from, `None` for original posts.
+fn reblog_root(post: &Value) -> Option<(Author, Url)> {
+    let trail = post["trail"].as_array().map(|x| x.as_slice());
+    let trail = trail.unwrap_or_default();
+    let root = trail
+        .iter()
+        .find(|x| x["is_root_item"].as_bool().unwrap_or_default())
+        .or_else(|| trail.first());
+    let (name, id, url, title) = match root {
+        Some(root) => (
+            root["blog"]["name"].as_str()?,
+            trail_post_id(&root["post"]["id"])?,
+            root["blog"]["url"].as_str(),
+            root["blog"]["title"].as_str(),
+        ),
+        None => (
+            post["reblogged_root_name"].as_str()?,
+            trail_post_id(&post["reblogged_root_id"])?,
+            post["reblogged_root_url"].as_str(),
+            post["reblogged_root_title"].as_str(),
+        ),
+    };
+    if Some(name) == post["blog_name"].as_str() {
+        return None;
+    }
+    let mut author = Author::new(name.to_string());
+    author.url = url
+        .and_then(|x| Url::from_str(x).ok())
+        .or_else(|| Url::from_str(&format!("https://{name}.tumblr.com/")).ok());
+    author.display_name = title
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| x.to_string());
+    let post_url = Url::from_str(&format!("https://{name}.tumblr.com/post/{id}")).ok()?;
+    Some((author, post_url))
+}
+
+fn trail_post_id(id: &Value) -> Option<String> {
+    match id {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// Lists the authors and sources of a post, starting with the original poster and root post
+/// of a reblog, followed by the reblog itself and the blogs that added to the trail.
+///
+/// The reblogging blog only counts as an author if it added content of its own.
+fn reblog_trail(
+    post: &Value,
+    own: Option<Author>,
+    post_url: Option<Url>,
+) -> (Vec<Author>, Vec<Url>) {
+    let mut authors: Vec<Author> = Vec::new();
+    let mut source_urls: Vec<Url> = Vec::new();
+    let root = reblog_root(post);
+    let is_reblog = root.is_some();
+    if let Some((author, url)) = root {
+        authors.push(author);
+        source_urls.push(url);
+    }
+    source_urls.extend(post_url);
+    let added_content = post["content"].as_array().is_some_and(|x| !x.is_empty())
+        || post["trail"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|x| x["is_current_item"].as_bool().unwrap_or_default());
+    if let Some(own) = own.filter(|_| !is_reblog || added_content) {
+        authors.push(own);
+    }
+    for item in post["trail"].as_array().into_iter().flatten() {
+        let name = match item["blog"]["name"].as_str() {
+            None => continue,
+            Some(name) => name,
+        };
+        if !authors.iter().any(|x| x.name == name) {
+            let mut author = Author::new(name.to_string());
+            author.url = Url::from_str(&format!("https://{name}.tumblr.com/")).ok();
+            authors.push(author);
+        }
+        let id = match trail_post_id(&item["post"]["id"]) {
+            None => continue,
+            Some(id) => id,
+        };
+        if let Ok(url) = Url::from_str(&format!("https://{name}.tumblr.com/post/{id}")) {
+            if !source_urls.contains(&url) {
+                source_urls.push(url);
+            }
         }
-        Some(i) => i,
+    }
+    (authors, source_urls)
+}
+
+/// Returns the variants of a media URL at its size and above, largest first.
+fn upsize_candidates(url: &Url) -> Vec<Url> {
+    let url = url.as_str();
+    let candidates: Vec<String> = if let Some(caps) = SIZE_REGEX.captures(url) {
+        let size: u64 = caps[1].parse().unwrap_or_default();
+        TUMBLR_SIZES
+            .iter()
+            .filter(|x| **x >= size)
+            .map(|x| {
+                SIZE_REGEX
+                    .replace(url, |caps: &Captures| format!("_{}{}", x, &caps[2]))
+                    .to_string()
+            })
+            .collect()
+    } else if let Some(caps) = DIMENSION_REGEX.captures(url) {
+        let width: u64 = caps[1].parse().unwrap_or_default();
+        TUMBLR_DIMENSIONS
+            .iter()
+            .filter(|(w, _)| *w >= width)
+            .map(|(w, h)| {
+                DIMENSION_REGEX
+                    .replace(url, |caps: &Captures| {
+                        let suffix = caps.get(3).map_or("", |x| x.as_str());
+                        format!("/s{w}x{h}{suffix}/")
+                    })
+                    .to_string()
+            })
+            .collect()
+    } else {
+        Vec::new()
     };
-    debug!("mapping {:?} to alt_size", image_url);
-    let mut urls = Vec::new();
-    let tumblr_sizes = TUMBLR_SIZES.clone();
-    for size in tumblr_sizes.iter() {
-        let image_url = SIZE_REGEX.replace(image_url, |caps: &Captures| {
-            format!("_{}{}", size, &caps[2])
-        });
-        let image_url = Url::from_str(&image_url)?;
-        trace!("found url: {}", image_url);
-        if url_ok(client, &image_url).await? {
-            trace!("url found valid: {}", image_url);
-            urls.push(image_url);
+    candidates
+        .into_iter()
+        .filter_map(|x| Url::from_str(&x).ok())
+        .collect()
+}
+
+/// Identifies a media file independent of the size in its URL.
+fn media_key(url: &Url) -> String {
+    let url = SIZE_REGEX.replace(url.as_str(), "$2");
+    DIMENSION_REGEX.replace(&url, "/${3}/").to_string()
+}
+
+/// Finds the largest available size of a Tumblr media URL, keeping the URL as is if no
+/// larger size exists.
+///
+/// Found sizes are remembered per media file so repeated scrapes don't probe again, misses
+/// are not.
+#[tracing::instrument(skip(cache, client))]
+async fn upsize(cache: &TumblrUpsizeCache, image_url: &Url, client: &Client) -> Url {
+    let candidates = upsize_candidates(image_url);
+    if candidates.is_empty() {
+        trace!("url has no known size, keeping it");
+        return image_url.clone();
+    }
+    let key = media_key(image_url);
+    if let Some(found) = cache.get(&key).await {
+        // a size found from a smaller starting point may be below this one
+        if candidates.contains(&found) {
+            return found;
         }
     }
-    match urls.first() {
-        None => Ok(None),
-        Some(image_url) => Ok(Some(image_url.clone())),
+    debug!("mapping {:?} to alt_size", image_url);
+    for candidate in candidates {
+        if url_ok(client, &candidate).await {
+            trace!("url found valid: {}", candidate);
+            cache.insert(key, candidate.clone()).await;
+            return candidate;
+        }
     }
+    debug!("no size of {} found, keeping it", image_url);
+    image_url.clone()
 }
 
+/// Whether a media URL exists, any other status or a failed request counts as missing.
 #[tracing::instrument(skip(client))]
-async fn url_ok(client: &Client, url: &Url) -> Result<bool> {
-    let resp = client.head(url.clone()).send().await?;
+async fn url_ok(client: &Client, url: &Url) -> bool {
     trace!("checking url {} for response", url);
-    if resp.status() == 200 {
-        trace!("url {} was ok", url);
-        Ok(true)
-    } else {
-        trace!("url {} was not ok: {}", url, resp.status());
-        Ok(false)
+    match client.head(url.clone()).send().await {
+        Ok(resp) if resp.status() == reqwest::StatusCode::OK => {
+            trace!("url {} was ok", url);
+            true
+        }
+        Ok(resp) => {
+            trace!("url {} returned {}", url, resp.status());
+            false
+        }
+        Err(e) => {
+            debug!("checking url {} failed: {:?}", url, e);
+            false
+        }
     }
 }
 
@@ -334,6 +782,201 @@ mod test {
     use super::*;
     use test_log::test;
 
+    #[test]
+    fn test_upsize_candidates() -> Result<()> {
+        let url = Url::parse("https://64.media.tumblr.com/abc/def-01/s540x810/a.png")?;
+        let candidates = upsize_candidates(&url);
+        assert_eq!(
+            vec![
+                "https://64.media.tumblr.com/abc/def-01/s2048x3072/a.png",
+                "https://64.media.tumblr.com/abc/def-01/s1280x1920/a.png",
+                "https://64.media.tumblr.com/abc/def-01/s640x960/a.png",
+                "https://64.media.tumblr.com/abc/def-01/s540x810/a.png",
+            ],
+            candidates.iter().map(|x| x.as_str()).collect::<Vec<_>>()
+        );
+        let url = Url::parse("https://66.media.tumblr.com/abc/tumblr_xyz_500.jpg")?;
+        assert_eq!(3, upsize_candidates(&url).len());
+        assert_eq!(
+            media_key(&url),
+            media_key(&Url::parse(
+                "https://66.media.tumblr.com/abc/tumblr_xyz_1280.jpg"
+            )?)
+        );
+        assert_ne!(
+            media_key(&url),
+            media_key(&Url::parse(
+                "https://66.media.tumblr.com/abc/tumblr_other_1280.jpg"
+            )?)
+        );
+        let url = Url::parse("https://64.media.tumblr.com/abc/def-01/s1280x1920_c1/a.png")?;
+        assert_eq!(
+            "https://64.media.tumblr.com/abc/def-01/s2048x3072_c1/a.png",
+            upsize_candidates(&url)[0].as_str()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_upsize() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        tokio_test::block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            let base = format!("http://{}", listener.local_addr()?);
+            let server = tokio::spawn(async move {
+                let mut requests = Vec::new();
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    let mut request = [0u8; 1024];
+                    let len = socket.read(&mut request).await?;
+                    let request = String::from_utf8_lossy(&request[..len]).to_string();
+                    let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+                    let status = if path.contains("broken/s2048") {
+                        "403 Forbidden"
+                    } else if path.contains("broken") {
+                        "500 Internal Server Error"
+                    } else if path.contains("s1280x1920") {
+                        "200 OK"
+                    } else {
+                        "404 Not Found"
+                    };
+                    let resp = format!(
+                        "HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                    );
+                    socket.write_all(resp.as_bytes()).await?;
+                    requests.push(path);
+                    if requests.len() == 7 {
+                        break;
+                    }
+                }
+                anyhow::Ok(requests)
+            });
+            let cache = TumblrUpsizeCache::new(10);
+            let client = crate::scraper::client(&Configuration::default())?;
+            let url = Url::parse(&format!("{base}/a/s540x810/b.png"))?;
+            let found = Url::parse(&format!("{base}/a/s1280x1920/b.png"))?;
+            assert_eq!(found, upsize(&cache, &url, &client).await);
+            // served from the cache, without asking the server again
+            assert_eq!(found, upsize(&cache, &url, &client).await);
+            // no size exists, the original is kept and nothing is cached
+            let url = Url::parse(&format!("{base}/c/tumblr_d_500.png"))?;
+            assert_eq!(url, upsize(&cache, &url, &client).await);
+            assert_eq!(None, cache.get(&media_key(&url)).await);
+            // refused and failing sizes are misses as well
+            let url = Url::parse(&format!("{base}/broken/s1280x1920/b.png"))?;
+            assert_eq!(url, upsize(&cache, &url, &client).await);
+            assert_eq!(None, cache.get(&media_key(&url)).await);
+            // two probes for the first image, three for the missing one and two for the broken one
+            let requests = server.await??;
+            assert_eq!(7, requests.len());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_upsize_images() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        tokio_test::block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            let base = format!("http://{}", listener.local_addr()?);
+            let server = tokio::spawn(async move {
+                for _ in 0..2 {
+                    let (mut socket, _) = listener.accept().await?;
+                    let mut request = [0u8; 1024];
+                    let len = socket.read(&mut request).await?;
+                    let request = String::from_utf8_lossy(&request[..len]).to_string();
+                    let status = if request.contains("s1280x1920") {
+                        "200 OK"
+                    } else {
+                        "404 Not Found"
+                    };
+                    let resp = format!(
+                        "HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                    );
+                    socket.write_all(resp.as_bytes()).await?;
+                }
+                anyhow::Ok(())
+            });
+            let url = Url::parse(&format!("{base}/a/s540x810/b.png"))?;
+            let mut image = ScrapeImage::new(url.clone(), url);
+            image.width = Some(540);
+            let video_url = Url::parse(&format!("{base}/a/s540x810/c.mp4"))?;
+            let mut video = ScrapeImage::new(video_url.clone(), video_url.clone());
+            video.media_type = MediaType::Video;
+            let mut images = vec![image, video];
+            let client = crate::scraper::client(&Configuration::default())?;
+            upsize_images(&TumblrUpsizeCache::new(10), &client, &mut images).await;
+            server.await??;
+            assert_eq!(format!("{base}/a/s1280x1920/b.png"), images[0].url.as_str());
+            assert_eq!(None, images[0].width);
+            assert_eq!(video_url, images[1].url);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_body_images() {
+        let body = r#"<p>two pictures</p>
+            <figure class="tmblr-full" data-orig-height="3000" data-orig-width="2000">
+            <img src="https://64.media.tumblr.com/abc/def-01/s640x960/a.png"
+                srcset="https://64.media.tumblr.com/abc/def-01/s100x200/a.png 100w, https://64.media.tumblr.com/abc/def-01/s2048x3072/a.png 2048w, https://64.media.tumblr.com/abc/def-01/s640x960/a.png 640w"></figure>
+            <p><img src="https://66.media.tumblr.com/tumblr_xyz_500.jpg"></p>
+            <img src="https://example.com/tracker.gif">"#;
+        assert_eq!(
+            vec![
+                Url::parse("https://64.media.tumblr.com/abc/def-01/s2048x3072/a.png").unwrap(),
+                Url::parse("https://66.media.tumblr.com/tumblr_xyz_500.jpg").unwrap(),
+            ],
+            body_images(body)
+        );
+    }
+
+    #[test]
+    fn test_reblog_trail() -> Result<()> {
+        let reblog = Url::parse("https://reblogger.tumblr.com/post/3")?;
+        let post = serde_json::json!({
+            "blog_name": "reblogger",
+            "content": [],
+            "trail": [
+                {"blog": {"name": "artist", "title": "The Artist"}, "post": {"id": "1"}},
+                {"blog": {"name": "commenter"}, "post": {"id": 2}},
+            ]
+        });
+        let own = Some(Author::new("reblogger".to_string()));
+        let (authors, source_urls) = reblog_trail(&post, own.clone(), Some(reblog.clone()));
+        assert_eq!(
+            vec!["artist", "commenter"],
+            authors.iter().map(|x| x.name.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some("The Artist".to_string()), authors[0].display_name);
+        assert_eq!(
+            vec![
+                Url::parse("https://artist.tumblr.com/post/1")?,
+                reblog.clone(),
+                Url::parse("https://commenter.tumblr.com/post/2")?,
+            ],
+            source_urls
+        );
+
+        let legacy = serde_json::json!({
+            "blog_name": "reblogger",
+            "reblogged_root_name": "artist",
+            "reblogged_root_id": 1,
+            "reblogged_root_url": "https://artist.example.com/",
+        });
+        let (authors, source_urls) = reblog_trail(&legacy, own.clone(), Some(reblog.clone()));
+        assert_eq!(
+            Some(Url::parse("https://artist.example.com/")?),
+            authors[0].url
+        );
+        assert_eq!(2, source_urls.len());
+
+        let original = serde_json::json!({"blog_name": "reblogger"});
+        let (authors, source_urls) = reblog_trail(&original, own, Some(reblog.clone()));
+        assert_eq!("reblogger", authors[0].name);
+        assert_eq!(vec![reblog], source_urls);
+        Ok(())
+    }
+
     #[test]
     #[ignore]
     fn test_tumblr_scraper() -> Result<()> {
@@ -346,27 +989,34 @@ mod test {
             return Ok(());
         }
         let scrape = tokio_test::block_on(scrape(&config, &state, url));
-        let scrape = match scrape {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
-        let scrape = match scrape {
-            Some(s) => s,
+        let scrape = scrape?;
+        let mut scrape = match scrape {
+            Some(s) => s.without_image_metadata(),
             None => anyhow::bail!("got none response from scraper"),
         };
-        let expected_result = ScrapeResult::Ok(ScrapeResultData{
+        if let ScrapeResult::Ok(data) = &mut scrape {
+            // blog titles can be changed by their owners, only check they are there
+            assert!(data.published_at.take().is_some());
+            assert!(data.author_display_name.take().is_some());
+            data.authors.iter_mut().for_each(|x| x.display_name = None);
+        }
+        let mut expected_result = ScrapeResultData{
             source_url: Some(Url::parse("https://tcn1205.tumblr.com/post/186904081532/in-wonderland")?),
             author_name: Some("tcn1205".to_string()),
             additional_tags: None,
             description: Some("In Wonderland.".to_string()),
             images: vec![
-                ScrapeImage{
-                    url: Url::parse("https://64.media.tumblr.com/cf3b6e5981e0aaf0f1be305429faa6c4/tumblr_pw0dzrDNvN1vlyxx7o1_1280.png")?,
-                    camo_url: Url::parse("https://64.media.tumblr.com/cf3b6e5981e0aaf0f1be305429faa6c4/tumblr_pw0dzrDNvN1vlyxx7o1_400.png")?,
-                }
+                ScrapeImage::new(
+                    Url::parse("https://64.media.tumblr.com/cf3b6e5981e0aaf0f1be305429faa6c4/tumblr_pw0dzrDNvN1vlyxx7o1_1280.png")?,
+                    Url::parse("https://64.media.tumblr.com/cf3b6e5981e0aaf0f1be305429faa6c4/tumblr_pw0dzrDNvN1vlyxx7o1_400.png")?,
+                )
             ],
-        });
-        assert_eq!(expected_result, scrape);
+            content_rating: Some(ContentRating::Safe),
+            author_url: Some(Url::parse("https://tcn1205.tumblr.com/")?),
+            ..Default::default()
+        };
+        expected_result.fill_authors_and_sources();
+        assert_eq!(ScrapeResult::Ok(Box::new(expected_result)), scrape);
         Ok(())
     }
 
@@ -382,27 +1032,34 @@ mod test {
             return Ok(());
         }
         let scrape = tokio_test::block_on(scrape(&config, &state, url));
-        let scrape = match scrape {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
-        let scrape = match scrape {
-            Some(s) => s,
+        let scrape = scrape?;
+        let mut scrape = match scrape {
+            Some(s) => s.without_image_metadata(),
             None => anyhow::bail!("got none response from scraper"),
         };
-        let expected_result = ScrapeResult::Ok(ScrapeResultData{
+        if let ScrapeResult::Ok(data) = &mut scrape {
+            // blog titles can be changed by their owners, only check they are there
+            assert!(data.published_at.take().is_some());
+            assert!(data.author_display_name.take().is_some());
+            data.authors.iter_mut().for_each(|x| x.display_name = None);
+        }
+        let mut expected_result = ScrapeResultData{
             source_url: Some(Url::parse("https://witchtaunter.tumblr.com/post/182898769998/yes-this-is-horse")?),
             author_name: Some("witchtaunter".to_string()),
             additional_tags: None,
             description: Some("Yes, this is horse".to_string()),
             images: vec![
-                ScrapeImage{
-                    url: Url::parse("https://64.media.tumblr.com/fbe494244d7e68e98e59141db4fddab7/tumblr_pn53n8VjWJ1s8a9ojo1_1280.png")?,
-                    camo_url: Url::parse("https://64.media.tumblr.com/fbe494244d7e68e98e59141db4fddab7/tumblr_pn53n8VjWJ1s8a9ojo1_400.png")?,
-                }
+                ScrapeImage::new(
+                    Url::parse("https://64.media.tumblr.com/fbe494244d7e68e98e59141db4fddab7/tumblr_pn53n8VjWJ1s8a9ojo1_1280.png")?,
+                    Url::parse("https://64.media.tumblr.com/fbe494244d7e68e98e59141db4fddab7/tumblr_pn53n8VjWJ1s8a9ojo1_400.png")?,
+                )
             ],
-        });
-        assert_eq!(expected_result, scrape);
+            content_rating: Some(ContentRating::Safe),
+            author_url: Some(Url::parse("https://witchtaunter.tumblr.com/")?),
+            ..Default::default()
+        };
+        expected_result.fill_authors_and_sources();
+        assert_eq!(ScrapeResult::Ok(Box::new(expected_result)), scrape);
         Ok(())
     }
 }
@@ -0,0 +1,489 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use reqwest_middleware::{ClientWithMiddleware as Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::Configuration;
+
+type HmacSha1 = Hmac<sha1::Sha1>;
+
+const TOKEN_URL: &str = "https://api.tumblr.com/v2/oauth2/token";
+
+/// Refresh access tokens this long before Tumblr expires them.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+/// After a failed refresh, requests skip OAuth2 for this long before it is tried again.
+const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Access token state of the OAuth2 credentials, shared by all requests.
+///
+/// Tumblr hands out a new refresh token with every access token and invalidates the old one,
+/// so the latest one is kept here rather than read from the configuration again, and written
+/// to the state file if one is configured.
+#[derive(Clone, Default)]
+pub struct TumblrAuth {
+    state: Arc<tokio::sync::Mutex<TokenState>>,
+    state_file: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct TokenState {
+    token: Option<AccessToken>,
+    retry_at: Option<Instant>,
+}
+
+struct AccessToken {
+    access_token: String,
+    refresh_token: String,
+    expires_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+    refresh_token: Option<String>,
+}
+
+/// Contents of the state file, tied to the configured refresh token so a new one replaces it.
+#[derive(Serialize, Deserialize)]
+struct StoredToken {
+    configured_sha256: String,
+    refresh_token: String,
+}
+
+impl TumblrAuth {
+    /// Checks that the configured credentials are complete, as both OAuth flavours sign with
+    /// the API key and its secret.
+    pub fn new(config: &Configuration) -> Result<Self> {
+        let oauth = config.tumblr_oauth2_refresh_token.is_some()
+            || config.tumblr_oauth_token.is_some()
+            || config.tumblr_oauth_token_secret.is_some();
+        if oauth {
+            anyhow::ensure!(
+                config
+                    .tumblr_api_key
+                    .as_deref()
+                    .is_some_and(|x| !x.trim().is_empty()),
+                "TUMBLR_API_KEY is required for the tumblr oauth credentials"
+            );
+            anyhow::ensure!(
+                config
+                    .tumblr_consumer_secret
+                    .as_deref()
+                    .is_some_and(|x| !x.trim().is_empty()),
+                "TUMBLR_CONSUMER_SECRET is required for the tumblr oauth credentials"
+            );
+        }
+        anyhow::ensure!(
+            config.tumblr_oauth_token.is_some() == config.tumblr_oauth_token_secret.is_some(),
+            "TUMBLR_OAUTH_TOKEN and TUMBLR_OAUTH_TOKEN_SECRET must be set together"
+        );
+        let state_file = config.tumblr_oauth2_state_file.as_ref().map(PathBuf::from);
+        if config.tumblr_oauth2_refresh_token.is_some() && state_file.is_none() {
+            warn!(
+                "TUMBLR_OAUTH2_STATE_FILE is not set, the refresh token Tumblr rotates is lost \
+                 on restart and TUMBLR_OAUTH2_REFRESH_TOKEN has to be replaced then"
+            );
+        }
+        Ok(Self {
+            state_file,
+            ..Default::default()
+        })
+    }
+
+    /// Builds an authorized GET request for an API URL, using OAuth2 if a refresh token is
+    /// configured, OAuth1 if a token and secret are, and the plain API key otherwise.
+    ///
+    /// If the OAuth2 access token can't be refreshed the next method is used instead, without
+    /// trying to refresh again for a while. `None` if no API key is configured at all.
+    #[tracing::instrument(skip(self, config, client))]
+    pub async fn request(
+        &self,
+        config: &Configuration,
+        client: &Client,
+        url: Url,
+    ) -> Option<RequestBuilder> {
+        let api_key = config
+            .tumblr_api_key
+            .as_deref()
+            .filter(|x| !x.trim().is_empty())?;
+        let consumer_secret = config.tumblr_consumer_secret.as_deref().unwrap_or_default();
+        if let Some(refresh_token) = &config.tumblr_oauth2_refresh_token {
+            if let Some(token) = self
+                .access_token(client, api_key, consumer_secret, refresh_token)
+                .await
+            {
+                return Some(client.get(url).bearer_auth(token));
+            }
+        }
+        if let (Some(token), Some(token_secret)) = (
+            &config.tumblr_oauth_token,
+            &config.tumblr_oauth_token_secret,
+        ) {
+            let header = oauth1_header(
+                "GET",
+                &url,
+                api_key,
+                consumer_secret,
+                token,
+                token_secret,
+                &uuid::Uuid::new_v4().simple().to_string(),
+                chrono::Utc::now().timestamp(),
+            );
+            return Some(client.get(url).header("authorization", header));
+        }
+        let mut url = url;
+        url.query_pairs_mut().append_pair("api_key", api_key);
+        Some(client.get(url))
+    }
+
+    /// Drops the current access token, so the next request refreshes it.
+    pub async fn invalidate(&self) {
+        if let Some(token) = self.state.lock().await.token.as_mut() {
+            token.expires_at = Instant::now();
+        }
+    }
+
+    /// Returns a valid access token, refreshing it if needed. `None` if the refresh failed,
+    /// now or within the last [`REFRESH_RETRY_DELAY`].
+    async fn access_token(
+        &self,
+        client: &Client,
+        client_id: &str,
+        client_secret: &str,
+        configured_refresh_token: &str,
+    ) -> Option<String> {
+        let mut state = self.state.lock().await;
+        if let Some(token) = state.token.as_ref() {
+            if token.expires_at > Instant::now() + EXPIRY_MARGIN {
+                return Some(token.access_token.clone());
+            }
+        }
+        if state.retry_at.is_some_and(|x| x > Instant::now()) {
+            debug!("tumblr access token refresh failed recently, not retrying yet");
+            return None;
+        }
+        let refresh_token = match state.token.as_ref() {
+            Some(token) => token.refresh_token.clone(),
+            None => match &self.state_file {
+                Some(path) => load_refresh_token(path, configured_refresh_token).await,
+                None => None,
+            }
+            .unwrap_or_else(|| configured_refresh_token.to_string()),
+        };
+        let resp = match refresh(client, client_id, client_secret, &refresh_token).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!("could not refresh tumblr access token: {:?}", e);
+                state.retry_at = Some(Instant::now() + REFRESH_RETRY_DELAY);
+                return None;
+            }
+        };
+        let refresh_token = resp.refresh_token.unwrap_or(refresh_token);
+        if let Some(path) = &self.state_file {
+            if let Err(e) = save_refresh_token(path, configured_refresh_token, &refresh_token).await
+            {
+                warn!("could not save tumblr refresh token to {:?}: {:?}", path, e);
+            }
+        }
+        state.retry_at = None;
+        state.token = Some(AccessToken {
+            access_token: resp.access_token.clone(),
+            refresh_token,
+            expires_at: Instant::now() + Duration::from_secs(resp.expires_in),
+        });
+        Some(resp.access_token)
+    }
+}
+
+async fn refresh(
+    client: &Client,
+    client_id: &str,
+    client_secret: &str,
+    refresh_token: &str,
+) -> Result<TokenResponse> {
+    debug!("refreshing tumblr access token");
+    client
+        .post(TOKEN_URL)
+        .form(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", client_id),
+            ("client_secret", client_secret),
+        ])
+        .send()
+        .await
+        .context("tumblr token request failed")?
+        .error_for_status()
+        .context("tumblr token request returned error code")?
+        .json()
+        .await
+        .context("could not parse tumblr token response")
+}
+
+/// Reads the latest refresh token from the state file, unless the file is missing or was
+/// written for a different configured refresh token.
+async fn load_refresh_token(path: &Path, configured: &str) -> Option<String> {
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(e) => {
+            debug!("could not read tumblr state file {:?}: {:?}", path, e);
+            return None;
+        }
+    };
+    let stored: StoredToken = match serde_json::from_slice(&data) {
+        Ok(stored) => stored,
+        Err(e) => {
+            warn!("could not parse tumblr state file {:?}: {:?}", path, e);
+            return None;
+        }
+    };
+    if stored.configured_sha256 != hex::encode(Sha256::digest(configured)) {
+        debug!("tumblr state file belongs to another refresh token, ignoring it");
+        return None;
+    }
+    Some(stored.refresh_token)
+}
+
+/// Writes the latest refresh token to the state file, replacing it in one step so a crash
+/// doesn't leave a partial file.
+async fn save_refresh_token(path: &Path, configured: &str, refresh_token: &str) -> Result<()> {
+    let stored = StoredToken {
+        configured_sha256: hex::encode(Sha256::digest(configured)),
+        refresh_token: refresh_token.to_string(),
+    };
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec(&stored)?)
+        .await
+        .context("could not write state file")?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .context("could not replace state file")
+}
+
+/// Percent encodes as required by OAuth1, leaving only unreserved characters.
+fn encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    out
+}
+
+/// Signs a request as described in RFC 5849, `params` holding the query and form parameters
+/// along with the `oauth_` ones.
+fn oauth1_signature(
+    method: &str,
+    base_url: &str,
+    params: &[(String, String)],
+    consumer_secret: &str,
+    token_secret: &str,
+) -> String {
+    let mut params: Vec<(String, String)> =
+        params.iter().map(|(k, v)| (encode(k), encode(v))).collect();
+    params.sort();
+    let params = params
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join("&");
+    let base = format!("{}&{}&{}", method, encode(base_url), encode(&params));
+    let key = format!("{}&{}", encode(consumer_secret), encode(token_secret));
+    let mut mac = HmacSha1::new_from_slice(key.as_bytes()).expect("hmac takes any key length");
+    mac.update(base.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn oauth1_header(
+    method: &str,
+    url: &Url,
+    consumer_key: &str,
+    consumer_secret: &str,
+    token: &str,
+    token_secret: &str,
+    nonce: &str,
+    timestamp: i64,
+) -> String {
+    let mut oauth = vec![
+        ("oauth_consumer_key".to_string(), consumer_key.to_string()),
+        ("oauth_nonce".to_string(), nonce.to_string()),
+        (
+            "oauth_signature_method".to_string(),
+            "HMAC-SHA1".to_string(),
+        ),
+        ("oauth_timestamp".to_string(), timestamp.to_string()),
+        ("oauth_token".to_string(), token.to_string()),
+        ("oauth_version".to_string(), "1.0".to_string()),
+    ];
+    let mut base_url = url.clone();
+    base_url.set_query(None);
+    base_url.set_fragment(None);
+    let params: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .chain(oauth.iter().cloned())
+        .collect();
+    let signature = oauth1_signature(
+        method,
+        base_url.as_str(),
+        &params,
+        consumer_secret,
+        token_secret,
+    );
+    oauth.push(("oauth_signature".to_string(), signature));
+    let fields = oauth
+        .iter()
+        .map(|(k, v)| format!(r#"{}="{}""#, encode(k), encode(v)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("OAuth {fields}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_oauth1_signature() {
+        // the example request of the Twitter API documentation on creating signatures
+        let params: Vec<(String, String)> = [
+            (
+                "status",
+                "Hello Ladies + Gentlemen, a signed OAuth request!",
+            ),
+            ("include_entities", "true"),
+            ("oauth_consumer_key", "xvz1evFS4wEEPTGEFPHBog"),
+            ("oauth_nonce", "kYjzVBB8Y0ZFabxSWbWovY3uYSQ2pTgmZeNu2VS4cg"),
+            ("oauth_signature_method", "HMAC-SHA1"),
+            ("oauth_timestamp", "1318622958"),
+            (
+                "oauth_token",
+                "370773112-GmHxMAgYyLbNEtIKZeRNFsMKPR9EyMZeS9weJAEb",
+            ),
+            ("oauth_version", "1.0"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(
+            "hCtSmYh+iHYCEqBWrE7C7hYmtUk=",
+            oauth1_signature(
+                "POST",
+                "https://api.twitter.com/1.1/statuses/update.json",
+                &params,
+                "kAcSOqF21Fu85e7zjz7ZN2U4ZRhfV3WpwPAoE3Z7kBw",
+                "LswwdoUaIvS8ltyTt5jkRh4J50vUPVVHtR2YPi5kE",
+            )
+        );
+    }
+
+    #[test]
+    fn test_oauth1_header() -> Result<()> {
+        let url = Url::parse("https://api.tumblr.com/v2/blog/staff/posts?id=1&npf=true")?;
+        let header = oauth1_header("GET", &url, "key", "secret", "token", "tsecret", "n", 1);
+        assert_eq!(
+            concat!(
+                r#"OAuth oauth_consumer_key="key", oauth_nonce="n", "#,
+                r#"oauth_signature_method="HMAC-SHA1", oauth_timestamp="1", "#,
+                r#"oauth_token="token", oauth_version="1.0", "#,
+                r#"oauth_signature="C1Thvjpq%2FeqwIrYvU92EZFYK1IY%3D""#,
+            ),
+            header
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_credentials() {
+        let config = Configuration {
+            tumblr_api_key: Some("key".to_string()),
+            tumblr_oauth2_refresh_token: Some("refresh".to_string()),
+            ..Default::default()
+        };
+        assert!(TumblrAuth::new(&config).is_err());
+        let config = Configuration {
+            tumblr_consumer_secret: Some("secret".to_string()),
+            ..config
+        };
+        assert!(TumblrAuth::new(&config).is_ok());
+        let config = Configuration {
+            tumblr_oauth_token: Some("token".to_string()),
+            ..config
+        };
+        assert!(TumblrAuth::new(&config).is_err());
+        assert!(TumblrAuth::new(&Configuration::default()).is_ok());
+    }
+
+    #[test]
+    fn test_refresh_token_state_file() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("scraper-tumblr-{}", uuid::Uuid::new_v4()));
+        tokio_test::block_on(async {
+            assert_eq!(None, load_refresh_token(&path, "configured").await);
+            save_refresh_token(&path, "configured", "rotated").await?;
+            assert_eq!(
+                Some("rotated".to_string()),
+                load_refresh_token(&path, "configured").await
+            );
+            // a newly configured refresh token takes precedence over the saved one
+            assert_eq!(None, load_refresh_token(&path, "replaced").await);
+            anyhow::Ok(())
+        })?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_backoff() -> Result<()> {
+        let config = Configuration {
+            tumblr_api_key: Some("key".to_string()),
+            tumblr_consumer_secret: Some("secret".to_string()),
+            tumblr_oauth2_refresh_token: Some("refresh".to_string()),
+            ..Default::default()
+        };
+        let auth = TumblrAuth::new(&config)?;
+        let url = Url::parse("https://api.tumblr.com/v2/blog/staff/info")?;
+        tokio_test::block_on(async {
+            let client = crate::scraper::client(&config)?;
+            // the token endpoint isn't reachable from tests, mark the refresh as just failed
+            auth.state.lock().await.retry_at = Some(Instant::now() + REFRESH_RETRY_DELAY);
+            let request = auth.request(&config, &client, url.clone()).await;
+            let request = request.context("no request")?.build()?;
+            assert!(request
+                .url()
+                .query()
+                .unwrap_or_default()
+                .contains("api_key=key"));
+            // and once an access token is there it is used
+            auth.state.lock().await.token = Some(AccessToken {
+                access_token: "access".to_string(),
+                refresh_token: "refresh".to_string(),
+                expires_at: Instant::now() + Duration::from_secs(3600),
+            });
+            let request = auth.request(&config, &client, url).await;
+            let request = request.context("no request")?.build()?;
+            assert_eq!(
+                Some("Bearer access"),
+                request
+                    .headers()
+                    .get("authorization")
+                    .and_then(|x| x.to_str().ok())
+            );
+            anyhow::Ok(())
+        })
+    }
+}
@@ -0,0 +1,317 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use serde_json::Value;
+use tracing::debug;
+use url::Url;
+
+use crate::{
+    camo::camo_url,
+    scraper::{
+        description::text_to_markdown, report_progress, video_mime_type, MediaType, ScrapeImage,
+        ScrapeProgress,
+    },
+    Configuration,
+};
+
+/// Whether the post came back in the Neue Post Format, made of content blocks.
+pub fn is_npf(post: &Value) -> bool {
+    post["content"].is_array()
+}
+
+/// Returns the indices of the content blocks in the order the layout displays them, blocks
+/// the layout doesn't mention follow in their original order.
+fn block_order(len: usize, layout: &Value) -> Vec<usize> {
+    let mut order = Vec::new();
+    let rows = layout
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|x| x["type"] == "rows")
+        .flat_map(|x| x["display"].as_array().into_iter().flatten());
+    for row in rows {
+        for index in row["blocks"].as_array().into_iter().flatten() {
+            match index.as_u64().and_then(|x| usize::try_from(x).ok()) {
+                Some(index) if index < len && !order.contains(&index) => order.push(index),
+                _ => (),
+            }
+        }
+    }
+    order.extend((0..len).filter(|x| !order.contains(x)).collect::<Vec<_>>());
+    order
+}
+
+/// Returns the content blocks of the reblog trail, root first, followed by those of the
+/// post itself, each in display order.
+fn blocks(post: &Value) -> Vec<&Value> {
+    post["trail"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .chain(std::iter::once(post))
+        .flat_map(|item| {
+            let content = item["content"].as_array().map(|x| x.as_slice());
+            let content = content.unwrap_or_default();
+            block_order(content.len(), &item["layout"])
+                .into_iter()
+                .map(move |index| &content[index])
+        })
+        .collect()
+}
+
+/// Collects the images and videos of a post and its reblog trail.
+#[tracing::instrument(skip(post, config))]
+pub fn process_post_npf(post: &Value, config: &Configuration) -> Result<Option<Vec<ScrapeImage>>> {
+    let mut images = Vec::new();
+    for block in blocks(post) {
+        let image = match block["type"].as_str() {
+            Some("image") => image_block(block, config)?,
+            Some("video") => video_block(block, config)?,
+            _ => None,
+        };
+        if let Some(image) = image {
+            report_progress(ScrapeProgress::ImageResolved {
+                image: Box::new(image.clone()),
+            });
+            images.push(image);
+        }
+    }
+    if images.is_empty() {
+        debug!("found no media blocks, bailing");
+        return Ok(None);
+    }
+    Ok(Some(images))
+}
+
+fn media_dimension(media: &Value, key: &str) -> Option<u32> {
+    media[key].as_u64().and_then(|x| u32::try_from(x).ok())
+}
+
+fn media_url(media: &Value) -> Option<Url> {
+    media["url"].as_str().and_then(|x| Url::from_str(x).ok())
+}
+
+/// Picks the largest entry of a media list, preferring the one in original dimensions.
+fn largest_media(media: &[Value]) -> Option<&Value> {
+    media
+        .iter()
+        .filter(|x| media_url(x).is_some())
+        .max_by_key(|x| media_rank(x))
+}
+
+fn media_rank(media: &Value) -> (bool, u32) {
+    (
+        media["has_original_dimensions"]
+            .as_bool()
+            .unwrap_or_default(),
+        media_dimension(media, "width").unwrap_or_default(),
+    )
+}
+
+fn image_block(block: &Value, config: &Configuration) -> Result<Option<ScrapeImage>> {
+    let media = block["media"].as_array().map(|x| x.as_slice());
+    let media = media.unwrap_or_default();
+    let best = match largest_media(media) {
+        None => return Ok(None),
+        Some(best) => best,
+    };
+    let url = media_url(best).expect("largest media has a URL");
+    // the smallest size that still makes a decent preview
+    let preview = media
+        .iter()
+        .filter(|x| media_dimension(x, "width").is_some_and(|x| x >= 400))
+        .min_by_key(|x| media_dimension(x, "width"))
+        .and_then(media_url)
+        .unwrap_or_else(|| url.clone());
+    let mut image = ScrapeImage::new(url, camo_url(config, &preview)?);
+    image.width = media_dimension(best, "width");
+    image.height = media_dimension(best, "height");
+    image.mime_type = best["type"].as_str().map(|x| x.to_string());
+    Ok(Some(image))
+}
+
+fn video_block(block: &Value, config: &Configuration) -> Result<Option<ScrapeImage>> {
+    // videos of other providers are only embedded and have no media of their own, and
+    // streaming playlists can't be imported
+    let media = match &block["media"] {
+        Value::Array(media) => media.iter().collect(),
+        media @ Value::Object(_) => vec![media],
+        _ => Vec::new(),
+    };
+    let best = media
+        .into_iter()
+        .filter_map(|x| {
+            let url = media_url(x)?;
+            let mime = video_mime_type(x["type"].as_str(), &url)?;
+            Some((x, url, mime))
+        })
+        .max_by_key(|(x, _, _)| media_rank(x));
+    let (media, url, mime) = match best {
+        None => {
+            debug!("video is not an MP4 or WebM hosted on tumblr, skipping");
+            return Ok(None);
+        }
+        Some(media) => media,
+    };
+    let poster = block["poster"]
+        .as_array()
+        .and_then(|x| largest_media(x))
+        .and_then(media_url)
+        .unwrap_or_else(|| url.clone());
+    let mut image = ScrapeImage::new(url, camo_url(config, &poster)?);
+    image.media_type = MediaType::Video;
+    image.width = media_dimension(media, "width");
+    image.height = media_dimension(media, "height");
+    image.mime_type = Some(mime.to_string());
+    Ok(Some(image))
+}
+
+/// Renders the text blocks of a post and its reblog trail as Markdown.
+pub fn npf_description(post: &Value) -> Option<String> {
+    let mut out = String::new();
+    let mut last_list = false;
+    for block in blocks(post).into_iter().filter(|x| x["type"] == "text") {
+        let subtype = block["subtype"].as_str().unwrap_or_default();
+        let list = subtype.ends_with("list-item");
+        if !out.is_empty() {
+            out.push_str(if list && last_list { "\n" } else { "\n\n" });
+        }
+        last_list = list;
+        let text = formatted_text(block);
+        match subtype {
+            "heading1" | "heading2" => out.push_str(&format!("**{}**", text.trim())),
+            "quote" | "indented" => out.push_str(&format!("> {}", text.replace('\n', "\n> "))),
+            "unordered-list-item" => out.push_str(&format!("* {text}")),
+            "ordered-list-item" => out.push_str(&format!("1. {text}")),
+            _ => out.push_str(&text),
+        }
+    }
+    let out = out.trim();
+    if out.is_empty() {
+        None
+    } else {
+        Some(out.to_string())
+    }
+}
+
+/// Applies the inline formatting of a text block, whose ranges count characters.
+fn formatted_text(block: &Value) -> String {
+    let text: Vec<char> = block["text"].as_str().unwrap_or_default().chars().collect();
+    // (position, closing, marker), closing markers sort before opening ones
+    let mut markers: Vec<(usize, bool, String)> = Vec::new();
+    for format in block["formatting"].as_array().into_iter().flatten() {
+        let range = (format["start"].as_u64(), format["end"].as_u64());
+        let (start, end) = match range {
+            (Some(start), Some(end)) if start < end => (start as usize, end as usize),
+            _ => continue,
+        };
+        if end > text.len() {
+            continue;
+        }
+        let (open, close) = match format["type"].as_str() {
+            Some("bold") => ("**".to_string(), "**".to_string()),
+            Some("italic") => ("*".to_string(), "*".to_string()),
+            Some("strikethrough") => ("~~".to_string(), "~~".to_string()),
+            Some("link") => match format["url"].as_str().and_then(|x| Url::from_str(x).ok()) {
+                Some(url) => ("[".to_string(), format!("]({url})")),
+                None => continue,
+            },
+            _ => continue,
+        };
+        markers.push((start, false, open));
+        markers.push((end, true, close));
+    }
+    markers.sort_by_key(|(position, closing, _)| (*position, !*closing));
+    let mut out = String::new();
+    let mut position = 0;
+    for (at, _, marker) in markers {
+        out.push_str(&text_to_markdown(
+            &text[position..at].iter().collect::<String>(),
+        ));
+        out.push_str(&marker);
+        position = at;
+    }
+    out.push_str(&text_to_markdown(
+        &text[position..].iter().collect::<String>(),
+    ));
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block_order() {
+        let layout = serde_json::json!([
+            {"type": "rows", "display": [{"blocks": [2]}, {"blocks": [0, 9]}]}
+        ]);
+        assert_eq!(vec![2, 0, 1], block_order(3, &layout));
+        assert_eq!(vec![0, 1], block_order(2, &Value::Null));
+    }
+
+    #[test]
+    fn test_process_post_npf() -> Result<()> {
+        let post = serde_json::json!({
+            "trail": [{
+                "blog": {"name": "artist"},
+                "post": {"id": "1"},
+                "content": [
+                    {"type": "text", "text": "my art, see site", "formatting": [
+                        {"start": 12, "end": 16, "type": "link", "url": "https://example.com/"},
+                        {"start": 0, "end": 6, "type": "bold"},
+                    ]},
+                    {"type": "image", "media": [
+                        {"url": "https://64.media.tumblr.com/a/s400x600/b.png", "width": 400, "height": 300, "type": "image/png"},
+                        {"url": "https://64.media.tumblr.com/a/s2048x3072/b.png", "width": 2000, "height": 1500, "type": "image/png", "has_original_dimensions": true},
+                        {"url": "https://64.media.tumblr.com/a/s1280x1920/b.png", "width": 1280, "height": 960, "type": "image/png"},
+                    ]},
+                ],
+                "layout": [{"type": "rows", "display": [{"blocks": [1]}, {"blocks": [0]}]}],
+            }],
+            "content": [
+                {"type": "video", "provider": "youtube", "url": "https://youtube.com/watch?v=x"},
+                {"type": "video", "provider": "tumblr",
+                    "media": {"url": "https://va.media.tumblr.com/tumblr_x.mp4", "type": "video/mp4", "width": 640, "height": 360},
+                    "poster": [{"url": "https://64.media.tumblr.com/tumblr_x_frame1.jpg", "width": 640, "height": 360}]},
+                {"type": "video", "provider": "tumblr", "media": [
+                    {"url": "https://va.media.tumblr.com/tumblr_y.m3u8", "type": "application/x-mpegURL", "width": 1280, "height": 720},
+                    {"url": "https://va.media.tumblr.com/tumblr_y.webm", "type": "video/webm", "width": 640, "height": 360},
+                ]},
+                {"type": "text", "subtype": "unordered-list-item", "text": "one"},
+                {"type": "text", "subtype": "unordered-list-item", "text": "two_three"},
+            ],
+        });
+        let images = process_post_npf(&post, &Configuration::default())?.unwrap();
+        assert_eq!(3, images.len());
+        assert_eq!(
+            "https://64.media.tumblr.com/a/s2048x3072/b.png",
+            images[0].url.as_str()
+        );
+        assert_eq!(
+            "https://64.media.tumblr.com/a/s400x600/b.png",
+            images[0].camo_url.as_str()
+        );
+        assert_eq!(
+            (Some(2000), Some(1500)),
+            (images[0].width, images[0].height)
+        );
+        assert_eq!(MediaType::Video, images[1].media_type);
+        assert_eq!(
+            "https://64.media.tumblr.com/tumblr_x_frame1.jpg",
+            images[1].camo_url.as_str()
+        );
+        assert_eq!(
+            "https://va.media.tumblr.com/tumblr_y.webm",
+            images[2].url.as_str()
+        );
+        assert_eq!(Some("video/webm".to_string()), images[2].mime_type);
+        assert_eq!(
+            Some(
+                "**my art**, see [site](https://example.com/)\n\n* one\n* two\\_three".to_string()
+            ),
+            npf_description(&post)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde_json::Value;
+use tracing::{debug, trace};
+use url::Url;
+
+use crate::Configuration;
+
+lazy_static::lazy_static! {
+    static ref STATE_REGEX: Regex = Regex::from_str(r#"(?s)window\['___INITIAL_STATE___'\]\s*=\s*(\{.*?\});\s*</script>"#)
+        .expect("failure in setting up essential regex");
+}
+
+const MAX_REDIRECTS: usize = 4;
+
+/// Fetches the public page of a post, as seen by a logged out visitor.
+#[tracing::instrument(skip(config))]
+pub async fn fetch_post_page(config: &Configuration, url: &Url) -> Result<String> {
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let client = crate::scraper::safe_client(config, &url).await?;
+        let resp = client
+            .get(url.clone())
+            .send()
+            .await
+            .context("request to tumblr page failed")?;
+        if resp.status().is_redirection() {
+            let location = resp
+                .headers()
+                .get("location")
+                .context("redirect without location")?
+                .to_str()
+                .context("redirect location invalid")?;
+            url = url.join(location).context("redirect location invalid")?;
+            trace!("following redirect to {}", url);
+            continue;
+        }
+        return resp
+            .error_for_status()
+            .context("tumblr page returned error code")?
+            .text()
+            .await
+            .context("could not read tumblr page");
+    }
+    anyhow::bail!("too many redirects")
+}
+
+/// Extracts the blog and post from a post page, shaped like the API response in NPF.
+///
+/// The embedded state of the web app is used where present, otherwise a single image post
+/// is made up from the Open Graph tags.
+pub fn post_from_page(body: &str, url: &Url, post_id: &str) -> Option<(Value, Value)> {
+    match initial_state(body).and_then(|state| find_post(&state, post_id).cloned()) {
+        Some(post) => {
+            let post = snake_case_keys(post);
+            let blog = post["blog"].clone();
+            Some((blog, post))
+        }
+        None => {
+            debug!("no embedded post state, falling back to open graph tags");
+            open_graph_post(body, url)
+        }
+    }
+}
+
+fn initial_state(body: &str) -> Option<Value> {
+    let dom = visdom::Vis::load(body).ok()?;
+    let script = dom.find("script#___INITIAL_STATE___").first().text();
+    let script = match script.trim() {
+        "" => STATE_REGEX.captures(body)?.get(1)?.as_str().to_string(),
+        script => script.to_string(),
+    };
+    serde_json::from_str(&script).ok()
+}
+
+/// Searches the state for the post with the given id.
+fn find_post<'a>(state: &'a Value, post_id: &str) -> Option<&'a Value> {
+    match state {
+        Value::Object(map) => {
+            let id_matches = match &map.get("id") {
+                Some(Value::String(id)) => id == post_id,
+                Some(Value::Number(id)) => id.to_string() == post_id,
+                _ => false,
+            };
+            if id_matches && map.get("content").is_some_and(|x| x.is_array()) {
+                return Some(state);
+            }
+            map.values().find_map(|x| find_post(x, post_id))
+        }
+        Value::Array(items) => items.iter().find_map(|x| find_post(x, post_id)),
+        _ => None,
+    }
+}
+
+/// The web app uses camel case where the API uses snake case.
+fn snake_case_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| (snake_case(&k), snake_case_keys(v)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(snake_case_keys).collect()),
+        value => value,
+    }
+}
+
+fn snake_case(key: &str) -> String {
+    let mut out = String::with_capacity(key.len() + 4);
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            out.push('_');
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn open_graph_post(body: &str, url: &Url) -> Option<(Value, Value)> {
+    let dom = visdom::Vis::load(body).ok()?;
+    let meta = |property: &str| {
+        dom.find(&format!(r#"meta[property="{property}"]"#))
+            .map(|_, x| x.get_attribute("content").map(|x| x.to_string()))
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+    };
+    let images = meta("og:image");
+    if images.is_empty() {
+        return None;
+    }
+    let mut content: Vec<Value> = images
+        .into_iter()
+        .map(|image| serde_json::json!({"type": "image", "media": [{"url": image}]}))
+        .collect();
+    if let Some(description) = meta("og:description").into_iter().next() {
+        content.push(serde_json::json!({"type": "text", "text": description}));
+    }
+    let blog_name = url
+        .host_str()
+        .and_then(|x| x.strip_suffix(".tumblr.com"))
+        .filter(|x| *x != "www")
+        .map(|x| x.to_string());
+    let post = serde_json::json!({
+        "blog_name": blog_name,
+        "post_url": url.as_str(),
+        "content": content,
+    });
+    Some((Value::Null, post))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_post_from_state() -> Result<()> {
+        let body = r#"<html><head><script type="application/json" id="___INITIAL_STATE___">
+            {"PeeprRoute": {"initialTimeline": {"objects": [
+                {"objectType": "post", "id": "123", "blogName": "artist",
+                 "postUrl": "https://www.tumblr.com/artist/123",
+                 "blog": {"name": "artist", "title": "Artist", "isNsfw": false},
+                 "content": [{"type": "image", "media": [
+                    {"url": "https://64.media.tumblr.com/a/s2048x3072/b.png", "width": 2048, "hasOriginalDimensions": true}
+                 ]}]}
+            ]}}}</script></head></html>"#;
+        let url = Url::parse("https://artist.tumblr.com/post/123")?;
+        let (blog, post) = post_from_page(body, &url, "123").expect("post in page");
+        assert_eq!("artist", post["blog_name"]);
+        assert_eq!(false, blog["is_nsfw"]);
+        assert_eq!(
+            true,
+            post["content"][0]["media"][0]["has_original_dimensions"]
+        );
+        assert!(post_from_page(body, &url, "999").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_post_from_open_graph() -> Result<()> {
+        let body = r#"<html><head>
+            <meta property="og:image" content="https://64.media.tumblr.com/a/s640x960/b.jpg">
+            <meta property="og:description" content="In Wonderland.">
+            </head></html>"#;
+        let url = Url::parse("https://tcn1205.tumblr.com/post/186904081532/in-wonderland")?;
+        let (_, post) = post_from_page(body, &url, "186904081532").expect("post in page");
+        assert_eq!("tcn1205", post["blog_name"]);
+        assert_eq!(
+            "https://64.media.tumblr.com/a/s640x960/b.jpg",
+            post["content"][0]["media"][0]["url"]
+        );
+        assert_eq!("In Wonderland.", post["content"][1]["text"]);
+        Ok(())
+    }
+}
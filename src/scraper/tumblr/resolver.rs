@@ -0,0 +1,149 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use hickory_resolver::proto::rr::RData;
+use hickory_resolver::TokioAsyncResolver;
+use ipnet::IpNet;
+use tracing::{debug, trace};
+
+use super::TumblrAuth;
+use crate::{Configuration, TumblrDnsCache};
+
+/// Custom domains of Tumblr blogs point here.
+const TUMBLR_CNAME: &str = "domains.tumblr.com";
+
+/// Decides whether a custom domain belongs to a Tumblr blog.
+///
+/// A domain counts if its CNAME chain reaches `domains.tumblr.com`, if it resolves into one of
+/// the `TUMBLR_IP_RANGES`, or, failing both, if the Tumblr API knows a blog by that name. The
+/// ranges are read once at startup, changing them needs a restart.
+#[derive(Clone)]
+pub struct TumblrResolver {
+    ranges: Arc<Vec<IpNet>>,
+    resolver: Option<TokioAsyncResolver>,
+    cache: TumblrDnsCache,
+}
+
+impl TumblrResolver {
+    pub fn new(config: &Configuration, cache: TumblrDnsCache) -> Result<Self> {
+        let ranges = config
+            .tumblr_ip_ranges
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| IpNet::from_str(x).with_context(|| format!("invalid tumblr ip range {x:?}")))
+            .collect::<Result<Vec<_>>>()?;
+        let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(resolver) => Some(resolver),
+            Err(e) => {
+                debug!(
+                    "no system resolver configuration, skipping dns checks: {:?}",
+                    e
+                );
+                None
+            }
+        };
+        Ok(Self {
+            ranges: Arc::new(IpNet::aggregate(&ranges)),
+            resolver,
+            cache,
+        })
+    }
+
+    fn in_ranges(&self, ip: IpAddr) -> bool {
+        self.ranges.iter().any(|net| net.contains(&ip))
+    }
+
+    #[tracing::instrument(skip(self, config, auth))]
+    pub async fn is_tumblr_host(
+        &self,
+        config: &Configuration,
+        auth: &TumblrAuth,
+        host: url::Host<&str>,
+    ) -> bool {
+        let domain = match host {
+            url::Host::Domain(domain) => domain.to_lowercase(),
+            url::Host::Ipv4(ip) => return self.in_ranges(ip.into()),
+            url::Host::Ipv6(ip) => return self.in_ranges(ip.into()),
+        };
+        if let Some(found) = self.cache.get(&domain).await {
+            return found;
+        }
+        match self.resolve(config, auth, &domain).await {
+            Some(found) => {
+                self.cache.insert(domain, found).await;
+                found
+            }
+            None => {
+                debug!("could not tell whether {} is a tumblr host", domain);
+                false
+            }
+        }
+    }
+
+    /// `None` if the domain couldn't be checked, which is not cached.
+    async fn resolve(
+        &self,
+        config: &Configuration,
+        auth: &TumblrAuth,
+        domain: &str,
+    ) -> Option<bool> {
+        if let Some(resolver) = &self.resolver {
+            // a fully qualified name, so the search domains of the host are not tried
+            match resolver.lookup_ip(format!("{domain}.")).await {
+                Ok(lookup) => {
+                    // the CNAME records followed on the way are kept in the answer
+                    let cnames: Vec<String> = lookup
+                        .as_lookup()
+                        .record_iter()
+                        .filter_map(|x| match x.data() {
+                            Some(RData::CNAME(target)) => Some(target.0.to_string()),
+                            _ => None,
+                        })
+                        .collect();
+                    trace!("cname chain: {:?}", cnames);
+                    if cnames.iter().any(|x| is_tumblr_cname(x)) {
+                        return Some(true);
+                    }
+                    if lookup.iter().any(|ip| self.in_ranges(ip)) {
+                        return Some(true);
+                    }
+                    trace!("host not in tumblr ranges");
+                }
+                Err(e) => debug!("host lookup failed: {:?}", e),
+            }
+        }
+        super::blog_exists(config, auth, domain).await
+    }
+}
+
+fn is_tumblr_cname(name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_lowercase();
+    name == TUMBLR_CNAME || name.ends_with(&format!(".{TUMBLR_CNAME}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tumblr_cname() {
+        assert!(is_tumblr_cname("domains.tumblr.com."));
+        assert!(is_tumblr_cname("Domains.Tumblr.com"));
+        assert!(!is_tumblr_cname("notdomains.tumblr.com"));
+        assert!(!is_tumblr_cname("domains.tumblr.com.example.com."));
+    }
+
+    #[test]
+    fn test_ip_hosts() -> Result<()> {
+        let config = Configuration::default();
+        let resolver = TumblrResolver::new(&config, TumblrDnsCache::new(10))?;
+        let auth = TumblrAuth::default();
+        let check = |host| tokio_test::block_on(resolver.is_tumblr_host(&config, &auth, host));
+        assert!(check(url::Host::Ipv4("66.6.33.1".parse()?)));
+        assert!(!check(url::Host::Ipv4("192.0.2.1".parse()?)));
+        Ok(())
+    }
+}
@@ -2,7 +2,11 @@ use std::{ops::Index, str::FromStr};
 
 use crate::scraper::ScrapeResult;
 use crate::scraper::ScrapeResultData;
-use crate::{scraper::ScrapeImage, Configuration};
+use crate::scraper::{report_progress, ScrapeProgress};
+use crate::{
+    scraper::{video_mime_type, ContentRating, MediaType, ScrapeImage},
+    Configuration,
+};
 use anyhow::{Context, Result};
 use regex::Regex;
 use reqwest_middleware::ClientWithMiddleware as Client;
@@ -152,6 +156,8 @@ pub async fn twitter_scrape(config: &Configuration, url: &Url) -> Result<Option<
     let mut api_response = make_api_request(&client, &api_url, &bearer, &gt)
         .await
         .context("invalid api response")?;
+    report_progress(ScrapeProgress::MetadataFetched);
+    let users = api_response.index("globalObjects").index("users").clone();
     use std::ops::IndexMut;
     let tweet = api_response.index_mut("globalObjects");
     let tweet = tweet.index_mut("tweets");
@@ -159,7 +165,12 @@ pub async fn twitter_scrape(config: &Configuration, url: &Url) -> Result<Option<
     let page_url = url::Url::from_str(&page_url).context("page url is not valid from API")?;
     let images = {
         let tweet = tweet.clone();
-        let media = tweet.index("entities").index("media").as_array();
+        // only extended_entities lists all media of a tweet and has the video variants
+        let media = tweet
+            .index("extended_entities")
+            .index("media")
+            .as_array()
+            .or_else(|| tweet.index("entities").index("media").as_array());
         let media: Vec<ScrapeImage> = match media {
             None => Vec::new(),
             Some(media) => media
@@ -174,10 +185,21 @@ pub async fn twitter_scrape(config: &Configuration, url: &Url) -> Result<Option<
                     let camo_url: anyhow::Result<Url> = crate::camo::camo_url(config, &url_orig);
                     let camo_url = camo_url.context("could not generate Camo url")?;
                     debug!("urls: {}, noorig: {}", url_orig, url_noorig);
-                    Ok(ScrapeImage {
-                        url: url_noorig,
-                        camo_url,
-                    })
+                    let kind = x.index("type").as_str().unwrap_or_default();
+                    let image = match best_video_variant(x) {
+                        Some((video_url, mime)) if matches!(kind, "video" | "animated_gif") => {
+                            let mut image = ScrapeImage::new(video_url, camo_url);
+                            image.media_type = MediaType::Video;
+                            image.mime_type = Some(mime.to_string());
+                            image.animated = Some(kind == "animated_gif");
+                            image
+                        }
+                        _ => ScrapeImage::new(url_noorig, camo_url),
+                    };
+                    report_progress(ScrapeProgress::ImageResolved {
+                        image: Box::new(image.clone()),
+                    });
+                    Ok(image)
                 })
                 .collect(),
         };
@@ -186,16 +208,81 @@ pub async fn twitter_scrape(config: &Configuration, url: &Url) -> Result<Option<
     if images.is_empty() {
         return Ok(None);
     }
-    Ok(Some(ScrapeResult::Ok(ScrapeResultData {
+    let tweet_user = users.index(tweet.index("user_id_str").as_str().unwrap_or_default());
+    Ok(Some(ScrapeResult::Ok(Box::new(ScrapeResultData {
         source_url: Some(url::Url::from_str(&url).context("source is not valid URL")?),
         author_name: Some(user.to_owned()),
-        additional_tags: None,
-        description: tweet.index("text").as_str().map_or_else(
-            || tweet.index("full_text").as_str().map(|f| f.to_owned()),
-            |f| Some(f.to_owned()),
-        ),
+        additional_tags: Some(
+            tweet["entities"]["hashtags"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|x| x["text"].as_str())
+                .map(|x| x.to_owned())
+                .collect::<Vec<_>>(),
+        )
+        .filter(|x| !x.is_empty()),
+        description: tweet_text(tweet),
         images,
-    })))
+        title: None,
+        published_at: tweet
+            .index("created_at")
+            .as_str()
+            .and_then(|x| crate::scraper::parse_date(x, "%a %b %d %H:%M:%S %z %Y")),
+        content_rating: tweet
+            .index("possibly_sensitive")
+            .as_bool()
+            .map(ContentRating::from_sensitive_flag),
+        author_url: Some(Url::from_str(&format!("https://twitter.com/{user}"))?),
+        author_display_name: tweet_user.index("name").as_str().map(|x| x.to_owned()),
+        authors: Vec::new(),
+        source_urls: Vec::new(),
+        unmapped_tags: Vec::new(),
+    }))))
+}
+
+/// Returns the text of a tweet as Markdown, with links expanded and links to its own media
+/// removed.
+fn tweet_text(tweet: &Value) -> Option<String> {
+    use htmlentity::entity::ICodedDataTrait;
+    let mut text = tweet
+        .index("full_text")
+        .as_str()
+        .or_else(|| tweet.index("text").as_str())?
+        .to_owned();
+    for link in tweet["entities"]["urls"].as_array().into_iter().flatten() {
+        if let (Some(url), Some(expanded)) = (link["url"].as_str(), link["expanded_url"].as_str()) {
+            text = text.replace(url, expanded);
+        }
+    }
+    let media = tweet["extended_entities"]["media"]
+        .as_array()
+        .or_else(|| tweet["entities"]["media"].as_array());
+    for media in media.into_iter().flatten() {
+        if let Some(url) = media["url"].as_str() {
+            text = text.replace(url, "");
+        }
+    }
+    let text = text.trim().to_owned();
+    let text = htmlentity::entity::decode(text.as_bytes())
+        .to_string()
+        .unwrap_or(text);
+    Some(super::description::text_to_markdown(&text))
+}
+
+/// Picks the MP4 or WebM variant with the highest bitrate of a video or animated GIF, along
+/// with its MIME type.
+fn best_video_variant(media: &Value) -> Option<(Url, &'static str)> {
+    media["video_info"]["variants"]
+        .as_array()?
+        .iter()
+        .filter_map(|variant| {
+            let url = Url::from_str(variant["url"].as_str()?).ok()?;
+            let mime = video_mime_type(variant["content_type"].as_str(), &url)?;
+            Some((variant["bitrate"].as_u64().unwrap_or_default(), url, mime))
+        })
+        .max_by_key(|(bitrate, _, _)| *bitrate)
+        .map(|(_, url, mime)| (url, mime))
 }
 
 #[cfg(test)]
@@ -219,18 +306,15 @@ mod test {
         parsed.set_fragment(None);
         parsed.set_query(None);
         let scrape = tokio_test::block_on(scrape(&config, &state, tweet));
-        let scrape = match scrape {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
+        let scrape = scrape?;
         let mut scrape = match scrape {
-            Some(s) => s,
+            Some(s) => s.without_image_metadata(),
             None => anyhow::bail!("got none response from scraper"),
         };
-        let test_results_expected = ScrapeImage {
-            url: url::Url::from_str("https://pbs.twimg.com/media/EwxvzkEXAAMFg7k.jpg")?,
-            camo_url: url::Url::from_str("https://pbs.twimg.com/media/EwxvzkEXAAMFg7k.jpg")?,
-        };
+        let test_results_expected = ScrapeImage::new(
+            url::Url::from_str("https://pbs.twimg.com/media/EwxvzkEXAAMFg7k.jpg")?,
+            url::Url::from_str("https://pbs.twimg.com/media/EwxvzkEXAAMFg7k.jpg")?,
+        );
         match &mut scrape {
             ScrapeResult::Ok(scrape) => {
                 for test_result in scrape.images.iter() {
@@ -241,13 +325,85 @@ mod test {
             ScrapeResult::Err(e) => panic!("error in scrape: {:?}", e.errors),
             ScrapeResult::None => panic!("no data in scrape"),
         }
-        assert_eq!(ScrapeResult::Ok(ScrapeResultData{
+        let mut expected = ScrapeResultData{
             source_url: Some(parsed),
             author_name: Some("TheOnion".to_string()),
             additional_tags: None,
-            description: Some("Deal Alert: The Federal Government Is Cutting You A $1,400 Stimulus Check That You Can, And Should, Spend Exclusively On 93 Copies Of ‘Stardew Valley’ https://t.co/RuRZN4XWIK https://t.co/tclZn8dQgg".to_string()),
+            description: Some("Deal Alert: The Federal Government Is Cutting You A $1,400 Stimulus Check That You Can, And Should, Spend Exclusively On 93 Copies Of ‘Stardew Valley’ https://bit.ly/3bX25sQ".to_string()),
             images: Vec::new(),
-        }), scrape);
+            published_at: Some(chrono::DateTime::parse_from_rfc3339("2021-03-18T17:05:05Z")?.into()),
+            content_rating: Some(ContentRating::Safe),
+            author_url: Some(url::Url::parse("https://twitter.com/TheOnion")?),
+            author_display_name: Some("The Onion".to_string()),
+            ..Default::default()
+        };
+        expected.fill_authors_and_sources();
+        assert_eq!(ScrapeResult::Ok(Box::new(expected)), scrape);
+        Ok(())
+    }
+
+    #[test]
+    fn test_created_at_format() {
+        assert_eq!(
+            Some("2018-10-10T20:19:24Z".to_string()),
+            crate::scraper::parse_date("Wed Oct 10 20:19:24 +0000 2018", "%a %b %d %H:%M:%S %z %Y")
+                .map(|x| x.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true))
+        );
+    }
+
+    #[test]
+    fn test_tweet_text() {
+        let tweet = serde_json::json!({
+            "full_text": "Fish &amp; chips https://t.co/abc https://t.co/media",
+            "entities": {
+                "urls": [{"url": "https://t.co/abc", "expanded_url": "https://example.com/a_b"}],
+                "media": [{"url": "https://t.co/media"}],
+            }
+        });
+        assert_eq!(
+            Some("Fish & chips https://example.com/a_b".to_string()),
+            tweet_text(&tweet)
+        );
+    }
+
+    #[test]
+    fn test_best_video_variant() -> Result<()> {
+        let media = serde_json::json!({
+            "type": "video",
+            "video_info": {
+                "variants": [
+                    {"bitrate": 832000, "content_type": "video/mp4", "url": "https://video.twimg.com/vid/480x270/a.mp4"},
+                    {"content_type": "application/x-mpegURL", "url": "https://video.twimg.com/pl/a.m3u8"},
+                    {"bitrate": 2176000, "content_type": "video/mp4", "url": "https://video.twimg.com/vid/1280x720/a.mp4"},
+                ]
+            }
+        });
+        assert_eq!(
+            Some((
+                Url::parse("https://video.twimg.com/vid/1280x720/a.mp4")?,
+                "video/mp4"
+            )),
+            best_video_variant(&media)
+        );
+        let media = serde_json::json!({
+            "type": "animated_gif",
+            "video_info": {
+                "variants": [
+                    {"bitrate": 0, "content_type": "video/webm", "url": "https://video.twimg.com/gif/a.webm"},
+                ]
+            }
+        });
+        assert_eq!(
+            Some((
+                Url::parse("https://video.twimg.com/gif/a.webm")?,
+                "video/webm"
+            )),
+            best_video_variant(&media)
+        );
+        assert_eq!(
+            None,
+            best_video_variant(&serde_json::json!({"type": "photo"}))
+        );
         Ok(())
     }
 }
@@ -2,8 +2,13 @@ use std::str::FromStr;
 
 use crate::scraper::ScrapeResult;
 use crate::scraper::ScrapeResultData;
-use crate::{scraper::ScrapeImage, Configuration};
+use crate::scraper::{report_progress, ScrapeProgress};
+use crate::{
+    scraper::{video_mime_type, ContentRating, MediaType, ScrapeImage},
+    Configuration,
+};
 use anyhow::Result;
+use htmlentity::entity::ICodedDataTrait;
 use itertools::Itertools;
 use twitter_v2::authorization::BearerToken;
 use twitter_v2::id::NumericId;
@@ -37,12 +42,16 @@ pub async fn twitter_v2_scrape(config: &Configuration, url: &Url) -> Result<Opti
             TweetField::CreatedAt,
             TweetField::AuthorId,
             TweetField::Attachments,
+            TweetField::PossiblySensitive,
+            TweetField::Entities,
         ])
         .expansions([TweetExpansion::AttachmentsMediaKeys])
         .media_fields([
             MediaField::Url,
             MediaField::PreviewImageUrl,
             MediaField::MediaKey,
+            MediaField::Type,
+            MediaField::Variants,
         ])
         .send()
         .await?;
@@ -69,13 +78,19 @@ pub async fn twitter_v2_scrape(config: &Configuration, url: &Url) -> Result<Opti
         None => return Ok(None),
         Some(user) => user,
     };
+    report_progress(ScrapeProgress::MetadataFetched);
 
     let images = match &media.media {
         None => vec![],
         Some(media) => media
             .iter()
             .filter_map(|image| {
-                let url = match &image.url {
+                let video = match image.kind {
+                    twitter_v2::data::MediaType::Photo => None,
+                    twitter_v2::data::MediaType::Video
+                    | twitter_v2::data::MediaType::AnimatedGif => best_video_variant(image),
+                };
+                let url = match video.as_ref().map(|(url, _)| url).or(image.url.as_ref()) {
                     None => return None,
                     Some(v) => v.clone(),
                 };
@@ -85,7 +100,17 @@ pub async fn twitter_v2_scrape(config: &Configuration, url: &Url) -> Result<Opti
                     .unwrap_or_else(|| url.clone());
                 let camo_url =
                     crate::camo::camo_url(config, &prev).expect("invalid tweet media uri");
-                Some(ScrapeImage { url, camo_url })
+                let mut scrape_image = ScrapeImage::new(url, camo_url);
+                if let Some((_, mime)) = video {
+                    scrape_image.media_type = MediaType::Video;
+                    scrape_image.mime_type = Some(mime.to_string());
+                    scrape_image.animated =
+                        Some(image.kind == twitter_v2::data::MediaType::AnimatedGif);
+                }
+                report_progress(ScrapeProgress::ImageResolved {
+                    image: Box::new(scrape_image.clone()),
+                });
+                Some(scrape_image)
             })
             .collect_vec(),
     };
@@ -94,11 +119,51 @@ pub async fn twitter_v2_scrape(config: &Configuration, url: &Url) -> Result<Opti
         return Ok(None);
     }
 
-    Ok(Some(ScrapeResult::Ok(ScrapeResultData {
+    Ok(Some(ScrapeResult::Ok(Box::new(ScrapeResultData {
         source_url: Some(url.clone()),
+        author_url: Some(Url::from_str(&format!(
+            "https://twitter.com/{}",
+            user.username
+        ))?),
         author_name: Some(user.username),
-        additional_tags: None,
-        description: Some(tweet.text.clone()),
+        additional_tags: tweet
+            .entities
+            .as_ref()
+            .and_then(|x| x.hashtags.as_ref())
+            .map(|x| x.iter().map(|x| x.tag.clone()).collect_vec())
+            .filter(|x| !x.is_empty()),
+        description: Some(super::description::text_to_markdown(
+            &htmlentity::entity::decode(tweet.text.as_bytes())
+                .to_string()
+                .unwrap_or_else(|_| tweet.text.clone()),
+        )),
         images,
-    })))
+        title: None,
+        published_at: tweet
+            .created_at
+            .and_then(|x| chrono::DateTime::from_timestamp(x.unix_timestamp(), 0)),
+        content_rating: tweet
+            .possibly_sensitive
+            .map(ContentRating::from_sensitive_flag),
+        author_display_name: Some(user.name),
+        authors: Vec::new(),
+        source_urls: Vec::new(),
+        unmapped_tags: Vec::new(),
+    }))))
+}
+
+/// Picks the MP4 or WebM variant with the highest bitrate of a video or animated GIF, along
+/// with its MIME type.
+fn best_video_variant(media: &twitter_v2::data::Media) -> Option<(Url, &'static str)> {
+    media
+        .variants
+        .as_ref()?
+        .iter()
+        .filter_map(|variant| {
+            let url = variant.url.clone()?;
+            let mime = video_mime_type(variant.content_type.as_deref(), &url)?;
+            Some((variant.bit_rate.unwrap_or_default(), url, mime))
+        })
+        .max_by_key(|(bitrate, _, _)| *bitrate)
+        .map(|(_, url, mime)| (url, mime))
 }
@@ -0,0 +1,236 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::Configuration;
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tracing::{debug, trace};
+use url::Url;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The cache directory is checked against `THUMBNAIL_CACHE_MAX_BYTES` at most this often.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    static ref LAST_PRUNE: std::sync::Mutex<Option<Instant>> = std::sync::Mutex::new(None);
+}
+
+/// Returns the URL of a thumbnail of `url` served by this scraper, if thumbnails are enabled.
+#[tracing::instrument(skip(config))]
+pub fn thumbnail_url(config: &Configuration, url: &Url) -> Result<Option<Url>> {
+    let (key, base_url) = match (&config.thumbnail_key, &config.thumbnail_base_url) {
+        (Some(key), Some(base_url)) => (key, base_url),
+        _ => return Ok(None),
+    };
+    let digest = hex::encode(sign(key, url.as_str())?);
+    let url = format!(
+        "{}/thumbnail/{}/{}",
+        base_url.as_str().trim_end_matches('/'),
+        digest,
+        hex::encode(url.as_str())
+    );
+    Ok(Some(Url::parse(&url).context("thumbnail url invalid")?))
+}
+
+/// Checks the signature of a thumbnail request and returns the URL it is for.
+#[tracing::instrument(skip(config))]
+pub fn verify_thumbnail_url(config: &Configuration, digest: &str, hex_url: &str) -> Result<Url> {
+    let key = config
+        .thumbnail_key
+        .as_ref()
+        .context("thumbnails are not enabled")?;
+    let url = String::from_utf8(hex::decode(hex_url).context("url is not valid hex")?)
+        .context("url is not valid utf-8")?;
+    let digest = hex::decode(digest).context("digest is not valid hex")?;
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes())?;
+    mac.update(url.as_bytes());
+    mac.verify_slice(&digest)
+        .map_err(|_| anyhow::format_err!("digest does not match"))?;
+    Url::parse(&url).context("url is not valid")
+}
+
+fn sign(key: &str, data: &str) -> Result<Vec<u8>> {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes())?;
+    mac.update(data.as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+fn cache_dir(config: &Configuration) -> PathBuf {
+    match &config.thumbnail_cache_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::temp_dir().join("scraper-thumbnails"),
+    }
+}
+
+/// Returns the content type and data of a thumbnail of `url`, from the disk cache if possible.
+#[tracing::instrument(skip(config))]
+pub async fn get_thumbnail(config: &Configuration, url: &Url) -> Result<(&'static str, Vec<u8>)> {
+    if crate::camo::is_denied(config, url) {
+        anyhow::bail!("refusing to fetch image from denied host");
+    }
+    let dir = cache_dir(config);
+    let name = format!(
+        "{}-{}",
+        hex::encode(Sha256::digest(url.as_str())),
+        config.thumbnail_size
+    );
+    for (ext, content_type) in [("png", "image/png"), ("jpg", "image/jpeg")] {
+        if let Ok(data) = tokio::fs::read(dir.join(format!("{name}.{ext}"))).await {
+            trace!("thumbnail cache hit");
+            return Ok((content_type, data));
+        }
+    }
+    let client = crate::scraper::safe_client(config, url).await?;
+    let data = crate::scraper::fetch_bytes(&client, url, config.thumbnail_max_bytes).await?;
+    let size = config.thumbnail_size;
+    let (ext, content_type, thumbnail) =
+        tokio::task::spawn_blocking(move || make_thumbnail(&data, size)).await??;
+    if let Err(e) = write_cache(&dir, &format!("{name}.{ext}"), &thumbnail).await {
+        debug!("could not cache thumbnail: {:?}", e);
+    }
+    if prune_due() {
+        if let Err(e) = prune_cache(&dir, config.thumbnail_cache_max_bytes).await {
+            debug!("could not prune thumbnail cache: {:?}", e);
+        }
+    }
+    Ok((content_type, thumbnail))
+}
+
+async fn write_cache(dir: &std::path::Path, name: &str, data: &[u8]) -> Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let tmp = dir.join(format!(".{name}.{}", uuid::Uuid::new_v4()));
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, dir.join(name)).await?;
+    Ok(())
+}
+
+fn prune_due() -> bool {
+    let mut last = LAST_PRUNE.lock().unwrap_or_else(|e| e.into_inner());
+    match *last {
+        Some(at) if at.elapsed() < PRUNE_INTERVAL => false,
+        _ => {
+            *last = Some(Instant::now());
+            true
+        }
+    }
+}
+
+/// Deletes the oldest thumbnails until the cache is no larger than `max_bytes`.
+async fn prune_cache(dir: &Path, max_bytes: u64) -> Result<()> {
+    let mut files = Vec::new();
+    let mut total: u64 = 0;
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        // dot files are thumbnails still being written
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let meta = entry.metadata().await?;
+        if !meta.is_file() {
+            continue;
+        }
+        total += meta.len();
+        files.push((meta.modified().ok(), meta.len(), entry.path()));
+    }
+    if total <= max_bytes {
+        return Ok(());
+    }
+    trace!("thumbnail cache holds {} bytes, pruning", total);
+    files.sort();
+    for (_, len, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => total -= len,
+            Err(e) => debug!("could not remove cached thumbnail {:?}: {:?}", path, e),
+        }
+    }
+    Ok(())
+}
+
+/// Scales an image down to fit into `size`x`size`, encoded as PNG if it has transparency
+/// and as JPEG otherwise.
+fn make_thumbnail(data: &[u8], size: u32) -> Result<(&'static str, &'static str, Vec<u8>)> {
+    let image = crate::scraper::decode_image(data)?;
+    let image = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+    let mut out = std::io::Cursor::new(Vec::new());
+    if image.color().has_alpha() {
+        image
+            .write_to(&mut out, image::ImageFormat::Png)
+            .context("could not encode thumbnail")?;
+        Ok(("png", "image/png", out.into_inner()))
+    } else {
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, 85);
+        image
+            .to_rgb8()
+            .write_with_encoder(encoder)
+            .context("could not encode thumbnail")?;
+        Ok(("jpg", "image/jpeg", out.into_inner()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_thumbnail_url_roundtrip() -> Result<()> {
+        let config = Configuration {
+            thumbnail_key: Some("key".to_string()),
+            thumbnail_base_url: Some(Url::parse("https://scraper.example.com/")?),
+            ..Default::default()
+        };
+        let url = Url::parse("https://example.com/image.png")?;
+        let thumb = thumbnail_url(&config, &url)?.expect("thumbnails are enabled");
+        let segments: Vec<&str> = thumb.path_segments().unwrap().collect();
+        assert_eq!("thumbnail", segments[0]);
+        assert_eq!(
+            url,
+            verify_thumbnail_url(&config, segments[1], segments[2])?
+        );
+        assert!(verify_thumbnail_url(&config, &"00".repeat(32), segments[2]).is_err());
+        assert_eq!(None, thumbnail_url(&Configuration::default(), &url)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_cache() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("scraper-prune-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        for name in ["a.jpg", "b.jpg", "c.jpg", ".d.jpg.tmp"] {
+            std::fs::write(dir.join(name), [0u8; 100])?;
+            // distinct modification times, oldest first
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        tokio_test::block_on(prune_cache(&dir, 250))?;
+        let mut left: Vec<String> = std::fs::read_dir(&dir)?
+            .map(|x| x.map(|x| x.file_name().to_string_lossy().to_string()))
+            .collect::<std::io::Result<_>>()?;
+        left.sort();
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(vec![".d.jpg.tmp", "b.jpg", "c.jpg"], left);
+        Ok(())
+    }
+
+    #[test]
+    fn test_make_thumbnail() -> Result<()> {
+        let mut data = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(1000, 500).write_to(&mut data, image::ImageFormat::Png)?;
+        let (ext, _, thumb) = make_thumbnail(&data.into_inner(), 250)?;
+        assert_eq!("jpg", ext);
+        let thumb = image::load_from_memory(&thumb)?;
+        assert_eq!((250, 125), (thumb.width(), thumb.height()));
+        // wider than the decoder limits allow
+        let mut data = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(20000, 1).write_to(&mut data, image::ImageFormat::Png)?;
+        assert!(make_thumbnail(&data.into_inner(), 250).is_err());
+        Ok(())
+    }
+}
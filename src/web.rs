@@ -1,24 +1,31 @@
 use crate::{
-    scraper::{self, ScrapeResult},
+    scraper::{self, ScrapeResult, ScrapeResultData, ScrapeResultError},
     Configuration, ResultCache, State,
 };
-use anyhow::Result;
 use axum::{
     extract::Query,
     http::{self, Request},
     middleware::Next,
-    response::{self, IntoResponse},
+    response::{
+        self,
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
     Json,
 };
+use futures::{Stream, StreamExt};
 use std::sync::Arc;
 use tokio::time::Instant;
 use tracing::debug;
 
-#[derive(serde::Deserialize, Clone, Debug)]
+#[derive(serde::Deserialize, Clone, securefmt::Debug, utoipa::ToSchema)]
 pub struct ScrapeRequest {
     url: String,
     #[serde(alias = "_method")]
     _method: Option<String>,
+    #[serde(alias = "_csrf_token")]
+    #[sensitive]
+    csrf_token: Option<String>,
 }
 
 #[allow(clippy::let_with_type_underscore)]
@@ -49,53 +56,485 @@ pub async fn origin_check(
     state: Arc<State>,
     next: Next,
 ) -> std::result::Result<impl response::IntoResponse, http::StatusCode> {
-    let origin = req.headers().get("Origin").map(|x| x.to_str()).transpose();
-    match origin {
-        Ok(origin) => {
-            if state.is_allowed_origin(origin) {
-                Ok(next.run(req).await)
-            } else {
-                Err(http::StatusCode::NOT_FOUND)
-            }
+    let origin = req
+        .headers()
+        .get(http::header::ORIGIN)
+        .map(|x| x.to_str())
+        .transpose();
+    let origin = match origin {
+        Ok(origin) => origin.map(|x| x.to_string()),
+        Err(_) => return Err(http::StatusCode::BAD_REQUEST),
+    };
+    if !state.is_allowed_origin(origin.as_deref()) {
+        debug!("rejecting request from origin {:?}", origin);
+        return Err(http::StatusCode::FORBIDDEN);
+    }
+    let mut res = if req.method() == http::Method::OPTIONS {
+        let mut res = response::Response::new(axum::body::Body::empty());
+        *res.status_mut() = http::StatusCode::NO_CONTENT;
+        let allowed_methods = if state.config.enable_get_request {
+            "GET, POST, OPTIONS"
+        } else {
+            "POST, OPTIONS"
+        };
+        let headers = res.headers_mut();
+        headers.insert(
+            http::header::ACCESS_CONTROL_ALLOW_METHODS,
+            http::HeaderValue::from_static(allowed_methods),
+        );
+        headers.insert(
+            http::header::ACCESS_CONTROL_ALLOW_HEADERS,
+            http::HeaderValue::from_static("content-type, x-csrf-token"),
+        );
+        headers.insert(
+            http::header::ACCESS_CONTROL_MAX_AGE,
+            http::HeaderValue::from_static("600"),
+        );
+        res
+    } else {
+        next.run(req).await
+    };
+    if let Some(origin) = origin {
+        let headers = res.headers_mut();
+        headers.insert(
+            http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            http::HeaderValue::from_str(&origin).map_err(|_| http::StatusCode::BAD_REQUEST)?,
+        );
+        headers.append(http::header::VARY, http::HeaderValue::from_static("origin"));
+    }
+    Ok(res)
+}
+
+/// Scrape response of the v2 API, tagged with an explicit `status`.
+#[derive(serde::Serialize, Debug, Clone, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ScrapeResponseV2 {
+    Ok(Box<ScrapeResultData>),
+    Error(ScrapeResultError),
+    None,
+}
+
+impl From<ScrapeResult> for ScrapeResponseV2 {
+    fn from(f: ScrapeResult) -> Self {
+        match f {
+            ScrapeResult::Ok(v) => Self::Ok(v),
+            ScrapeResult::Err(e) => Self::Error(e),
+            ScrapeResult::None => Self::None,
         }
-        Err(_) => Err(http::StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
 
-#[tracing::instrument(skip(state))]
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    paths(
+        scrape_post,
+        scrape,
+        scrape_v2_post,
+        scrape_v2,
+        scrape_events,
+        scrape_events_post,
+        submit_job,
+        job_status
+    ),
+    components(schemas(
+        ScrapeRequest,
+        ScrapeResult,
+        ScrapeResultData,
+        ScrapeResultError,
+        scraper::ScrapeImage,
+        scraper::MediaType,
+        scraper::ContentRating,
+        scraper::Author,
+        ScrapeResponseV2,
+        JobRequest,
+        crate::jobs::Job,
+        crate::jobs::JobStatus,
+    ))
+)]
+pub struct ApiDoc;
+
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    use utoipa::OpenApi;
+    Json(ApiDoc::openapi())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/scrape",
+    request_body = ScrapeRequest,
+    responses(
+        (status = 200, description = "Scrape result", body = ScrapeResult),
+        (status = 403, description = "CSRF token missing or invalid", body = ScrapeResultError),
+    )
+)]
+#[tracing::instrument(skip(state, headers))]
 pub async fn scrape_post(
     axum::extract::State(state): axum::extract::State<Arc<State>>,
+    headers: http::HeaderMap,
     Json(scrape_req): Json<ScrapeRequest>,
 ) -> response::Response<String> {
-    match scrape_inner(
+    if csrf_rejected(&state, &headers, scrape_req.csrf_token.as_deref()) {
+        return json_response(http::StatusCode::FORBIDDEN, &csrf_rejection());
+    }
+    let res = scrape_inner(
         &state.config,
         state.result_cache.clone(),
-        &state.clone(),
+        &state,
         scrape_req,
     )
-    .await
-    {
-        Ok(v) => v,
-        Err(_) => todo!(),
+    .await;
+    json_response(http::StatusCode::OK, &v1_result(res))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v2/scrape",
+    request_body = ScrapeRequest,
+    responses(
+        (status = 200, description = "Scrape result", body = ScrapeResponseV2),
+        (status = 403, description = "CSRF token missing or invalid", body = ScrapeResponseV2),
+    )
+)]
+#[tracing::instrument(skip(state, headers))]
+pub async fn scrape_v2_post(
+    axum::extract::State(state): axum::extract::State<Arc<State>>,
+    headers: http::HeaderMap,
+    Json(scrape_req): Json<ScrapeRequest>,
+) -> response::Response<String> {
+    if csrf_rejected(&state, &headers, scrape_req.csrf_token.as_deref()) {
+        return json_response(
+            http::StatusCode::FORBIDDEN,
+            &ScrapeResponseV2::from(csrf_rejection()),
+        );
+    }
+    let res = scrape_inner(
+        &state.config,
+        state.result_cache.clone(),
+        &state,
+        scrape_req,
+    )
+    .await;
+    json_response(http::StatusCode::OK, &ScrapeResponseV2::from(res))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v2/scrape",
+    params(("url" = String, Query, description = "URL to scrape")),
+    responses((status = 200, description = "Scrape result", body = ScrapeResponseV2))
+)]
+#[tracing::instrument(skip(state))]
+pub async fn scrape_v2(
+    axum::extract::State(state): axum::extract::State<Arc<State>>,
+    Query(scrape_req): Query<ScrapeRequest>,
+) -> response::Response<String> {
+    let res = scrape_inner(
+        &state.config,
+        state.result_cache.clone(),
+        &state,
+        scrape_req,
+    )
+    .await;
+    json_response(http::StatusCode::OK, &ScrapeResponseV2::from(res))
+}
+
+fn csrf_rejected(state: &State, headers: &http::HeaderMap, form_token: Option<&str>) -> bool {
+    if !state.config.check_csrf_presence {
+        return false;
+    }
+    let token = headers
+        .get(crate::csrf::CSRF_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .or(form_token);
+    if crate::csrf::csrf_valid(&state.config, token) {
+        return false;
+    }
+    debug!("rejecting request with missing or invalid CSRF token");
+    true
+}
+
+/// The v1 API reports URLs no scraper supports as an error rather than `null`.
+fn v1_result(res: ScrapeResult) -> ScrapeResult {
+    match res {
+        ScrapeResult::None => ScrapeResult::Err("URL invalid".to_string().into()),
+        res => res,
+    }
+}
+
+fn csrf_rejection() -> ScrapeResult {
+    ScrapeResult::Err("CSRF token missing or invalid".to_string().into())
+}
+
+fn json_response<T: serde::Serialize>(
+    status: http::StatusCode,
+    body: &T,
+) -> response::Response<String> {
+    response::Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(body).unwrap_or_default())
+        .unwrap()
+}
+
+#[derive(serde::Deserialize, Clone, securefmt::Debug, utoipa::ToSchema)]
+pub struct JobRequest {
+    url: String,
+    callback_url: Option<url::Url>,
+    #[serde(alias = "_csrf_token")]
+    #[sensitive]
+    csrf_token: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    request_body = JobRequest,
+    responses(
+        (status = 202, description = "Job was queued", body = crate::jobs::Job),
+        (status = 403, description = "CSRF token missing or invalid", body = ScrapeResultError),
+        (status = 400, description = "Invalid job", body = ScrapeResultError),
+        (status = 503, description = "Job queue is full", body = ScrapeResultError),
+    )
+)]
+#[tracing::instrument(skip(state, headers))]
+pub async fn submit_job(
+    axum::extract::State(state): axum::extract::State<Arc<State>>,
+    headers: http::HeaderMap,
+    Json(job_req): Json<JobRequest>,
+) -> response::Response<String> {
+    if csrf_rejected(&state, &headers, job_req.csrf_token.as_deref()) {
+        return json_response(http::StatusCode::FORBIDDEN, &csrf_rejection());
+    }
+    match state.jobs.submit(job_req.url, job_req.callback_url).await {
+        Ok(Some(job)) => json_response(http::StatusCode::ACCEPTED, &job),
+        Ok(None) => json_response(
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            &ScrapeResult::Err("job queue is full".to_string().into()),
+        ),
+        Err(e) => json_response(
+            http::StatusCode::BAD_REQUEST,
+            &ScrapeResult::from_err(Arc::new(e)),
+        ),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "Job ID")),
+    responses(
+        (status = 200, description = "Job status", body = crate::jobs::Job),
+        (status = 404, description = "Unknown job", body = ScrapeResultError),
+    )
+)]
+#[tracing::instrument(skip(state))]
+pub async fn job_status(
+    axum::extract::State(state): axum::extract::State<Arc<State>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> response::Response<String> {
+    match state.jobs.get(&id).await {
+        Some(job) => json_response(http::StatusCode::OK, &job),
+        None => json_response(
+            http::StatusCode::NOT_FOUND,
+            &ScrapeResult::Err("job not found".to_string().into()),
+        ),
     }
 }
 
+/// Serves a signed thumbnail URL as created by [`crate::thumbnail::thumbnail_url`].
+#[tracing::instrument(skip(state))]
+pub async fn thumbnail(
+    axum::extract::State(state): axum::extract::State<Arc<State>>,
+    axum::extract::Path((digest, url)): axum::extract::Path<(String, String)>,
+) -> response::Response<axum::body::Body> {
+    let error = |status: http::StatusCode| {
+        response::Response::builder()
+            .status(status)
+            .body(axum::body::Body::empty())
+            .unwrap()
+    };
+    let url = match crate::thumbnail::verify_thumbnail_url(&state.config, &digest, &url) {
+        Ok(url) => url,
+        Err(e) => {
+            debug!("rejecting thumbnail request: {:?}", e);
+            return error(http::StatusCode::FORBIDDEN);
+        }
+    };
+    match crate::thumbnail::get_thumbnail(&state.config, &url).await {
+        Ok((content_type, data)) => response::Response::builder()
+            .status(http::StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, content_type)
+            .header(
+                http::header::CACHE_CONTROL,
+                "public, max-age=31536000, immutable",
+            )
+            .header(http::header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+            .body(axum::body::Body::from(data))
+            .unwrap(),
+        Err(e) => {
+            debug!("could not create thumbnail: {:?}", e);
+            error(http::StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ProxyQuery {
+    url: String,
+}
+
+/// Serves a Camo URL of the form `/<digest>?url=<url>`.
+#[tracing::instrument(skip(state, headers))]
+pub async fn proxy(
+    axum::extract::State(state): axum::extract::State<Arc<State>>,
+    headers: http::HeaderMap,
+    axum::extract::Path(digest): axum::extract::Path<String>,
+    Query(query): Query<ProxyQuery>,
+) -> response::Response<axum::body::Body> {
+    proxy_inner(&state, &headers, &digest, Ok(query.url)).await
+}
+
+/// Serves a Camo URL of the form `/<digest>/<hex url>`.
+#[tracing::instrument(skip(state, headers))]
+pub async fn proxy_hex(
+    axum::extract::State(state): axum::extract::State<Arc<State>>,
+    headers: http::HeaderMap,
+    axum::extract::Path((digest, url)): axum::extract::Path<(String, String)>,
+) -> response::Response<axum::body::Body> {
+    let url = crate::proxy::decode_hex_url(&url);
+    proxy_inner(&state, &headers, &digest, url).await
+}
+
+async fn proxy_inner(
+    state: &State,
+    headers: &http::HeaderMap,
+    digest: &str,
+    url: anyhow::Result<String>,
+) -> response::Response<axum::body::Body> {
+    let error = |status: http::StatusCode| {
+        response::Response::builder()
+            .status(status)
+            .body(axum::body::Body::empty())
+            .unwrap()
+    };
+    let url = url.and_then(|url| crate::proxy::verify_proxy_url(&state.config, digest, &url));
+    let url = match url {
+        Ok(url) => url,
+        Err(e) => {
+            debug!("rejecting proxy request: {:?}", e);
+            return error(http::StatusCode::FORBIDDEN);
+        }
+    };
+    match crate::proxy::proxy_image(&state.config, &url, headers).await {
+        Ok(res) => res,
+        Err(e) => {
+            debug!("could not proxy image: {:?}", e);
+            error(http::StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/scrape",
+    params(("url" = String, Query, description = "URL to scrape")),
+    responses((status = 200, description = "Scrape result", body = ScrapeResult))
+)]
 #[tracing::instrument(skip(state))]
 pub async fn scrape(
     axum::extract::State(state): axum::extract::State<Arc<State>>,
     Query(scrape_req): Query<ScrapeRequest>,
 ) -> response::Response<String> {
-    match scrape_inner(
+    let res = scrape_inner(
         &state.config,
         state.result_cache.clone(),
-        &state.clone(),
+        &state,
         scrape_req,
     )
-    .await
-    {
-        Ok(v) => v,
-        Err(_) => todo!(),
+    .await;
+    json_response(http::StatusCode::OK, &v1_result(res))
+}
+
+#[utoipa::path(
+    get,
+    path = "/images/scrape/events",
+    params(("url" = String, Query, description = "URL to scrape")),
+    responses((
+        status = 200,
+        description = "Server-sent `progress` events, followed by a `result` event with the scrape result, `null` if no scraper supports the URL",
+        content_type = "text/event-stream",
+        body = ScrapeResult,
+    ))
+)]
+#[tracing::instrument(skip(state))]
+pub async fn scrape_events(
+    axum::extract::State(state): axum::extract::State<Arc<State>>,
+    Query(scrape_req): Query<ScrapeRequest>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>> {
+    scrape_event_stream(state, scrape_req)
+}
+
+#[utoipa::path(
+    post,
+    path = "/images/scrape/events",
+    request_body = ScrapeRequest,
+    responses(
+        (
+            status = 200,
+            description = "Server-sent `progress` events, followed by a `result` event with the scrape result, `null` if no scraper supports the URL",
+            content_type = "text/event-stream",
+            body = ScrapeResult,
+        ),
+        (status = 403, description = "CSRF token missing or invalid", body = ScrapeResultError),
+    )
+)]
+#[tracing::instrument(skip(state, headers))]
+pub async fn scrape_events_post(
+    axum::extract::State(state): axum::extract::State<Arc<State>>,
+    headers: http::HeaderMap,
+    Json(scrape_req): Json<ScrapeRequest>,
+) -> response::Response {
+    if csrf_rejected(&state, &headers, scrape_req.csrf_token.as_deref()) {
+        return json_response(http::StatusCode::FORBIDDEN, &csrf_rejection()).into_response();
     }
+    scrape_event_stream(state, scrape_req).into_response()
+}
+
+fn scrape_event_stream(
+    state: Arc<State>,
+    scrape_req: ScrapeRequest,
+) -> Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let result = tokio::spawn(async move {
+        let url = scrape_req.url;
+        let res = state
+            .result_cache
+            .try_get_with(
+                url.clone(),
+                scraper::scrape_with_progress(&state.config, &state, &url, tx),
+            )
+            .await;
+        match res {
+            Ok(Some(res)) => res,
+            Ok(None) => ScrapeResult::None,
+            Err(e) => {
+                sentry::integrations::anyhow::capture_anyhow(&e);
+                ScrapeResult::from_err(e)
+            }
+        }
+    });
+    let progress = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|progress| (progress, rx))
+    })
+    .map(|progress| Event::default().event(progress.stage()).json_data(progress));
+    let result = futures::stream::once(async move {
+        let result = match result.await {
+            Ok(result) => result,
+            Err(e) => ScrapeResult::from_err(Arc::new(e.into())),
+        };
+        Event::default().event("result").json_data(result)
+    });
+    Sse::new(progress.chain(result)).keep_alive(KeepAlive::default())
 }
 
 #[tracing::instrument(skip(request_cache, state, config))]
@@ -104,35 +543,49 @@ pub async fn scrape_inner(
     request_cache: ResultCache,
     state: &State,
     scrape_req: ScrapeRequest,
-) -> Result<response::Response<String>> {
+) -> ScrapeResult {
     let url = scrape_req.url.clone();
     let res: std::result::Result<Option<ScrapeResult>, Arc<anyhow::Error>> = request_cache
         .try_get_with(scrape_req.url, scraper::scrape(config, state, &url))
         .await;
-    let res = match res {
-        Ok(r) => r,
+    match res {
+        Ok(Some(res)) => res,
+        Ok(None) => ScrapeResult::None,
         Err(e) => {
             sentry::integrations::anyhow::capture_anyhow(&e);
-            let e = ScrapeResult::from_err(e);
-            return Ok(response::Response::builder()
-                .status(http::StatusCode::OK)
-                .header(http::header::CONTENT_TYPE, "application/json")
-                .body(serde_json::to_string(&e)?)?);
+            ScrapeResult::from_err(e)
         }
-    };
-    let res = match res {
-        Some(res) => res,
-        None => {
-            return Ok(response::Response::builder()
-                .status(http::StatusCode::OK)
-                .header(http::header::CONTENT_TYPE, "application/json")
-                .body(serde_json::to_string(&ScrapeResult::Err(
-                    "URL invalid".to_string().into(),
-                ))?)?);
-        }
-    };
-    Ok(response::Response::builder()
-        .status(http::StatusCode::OK)
-        .header(http::header::CONTENT_TYPE, "application/json")
-        .body(serde_json::to_string(&res)?)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_v2_response_is_tagged() -> anyhow::Result<()> {
+        let none = serde_json::to_value(ScrapeResponseV2::from(ScrapeResult::None))?;
+        assert_eq!(serde_json::json!({"status": "none"}), none);
+        assert!(matches!(
+            v1_result(ScrapeResult::None),
+            ScrapeResult::Err(_)
+        ));
+        let err = ScrapeResponseV2::from(ScrapeResult::Err("URL invalid".to_string().into()));
+        assert_eq!(
+            serde_json::json!({"status": "error", "errors": ["URL invalid"]}),
+            serde_json::to_value(err)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_openapi_document() {
+        use utoipa::OpenApi;
+        let doc = ApiDoc::openapi();
+        assert!(doc.paths.paths.contains_key("/api/v2/scrape"));
+        assert!(doc.paths.paths.contains_key("/jobs/{id}"));
+        let events = &doc.paths.paths["/images/scrape/events"].operations;
+        assert!(events.contains_key(&utoipa::openapi::PathItemType::Get));
+        assert!(events.contains_key(&utoipa::openapi::PathItemType::Post));
+    }
 }